@@ -37,6 +37,7 @@ pub(crate) fn update_annotations(
 // qualified CDI device names. If any device fails this check empty slices
 // are returned along with a non-nil error. The annotations are expected
 // to be formatted by, or in a compatible fashion to UpdateAnnotations().
+// Requests are collected in the order of their keys.
 #[allow(dead_code)]
 pub fn parse_annotations(
     annotations: &HashMap<String, String>,
@@ -44,6 +45,8 @@ pub fn parse_annotations(
     let mut keys: Vec<String> = Vec::new();
     let mut devices: Vec<String> = Vec::new();
 
+    let mut annotations: Vec<_> = annotations.iter().collect();
+    annotations.sort();
     for (k, v) in annotations {
         if !k.starts_with(ANNOTATION_PREFIX) {
             continue;
@@ -0,0 +1,234 @@
+use std::{
+    pin::Pin,
+    sync::{Arc, Once},
+    task::{Context, Poll},
+};
+
+use futures_core::Stream;
+use oci_spec::runtime as oci;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+use crate::{
+    cache::{Cache, CacheEvent},
+    error::CdiError,
+};
+
+// AsyncCache is a handle for using a Cache from async code. The blocking
+// file system work of refreshes, queries and injections is moved to the
+// blocking thread pool of the tokio runtime. The handle is cheap to clone,
+// all clones share the same Cache.
+#[derive(Clone)]
+pub struct AsyncCache {
+    cache: Arc<Cache>,
+    // refresher is started by the first events() call.
+    refresher: Arc<Once>,
+}
+
+impl From<Cache> for AsyncCache {
+    fn from(cache: Cache) -> Self {
+        Self::new(cache)
+    }
+}
+
+impl AsyncCache {
+    // new returns an async handle for the given Cache. Options need to be
+    // applied to the Cache before, see new_cache().
+    pub fn new(cache: Cache) -> Self {
+        Self {
+            cache: Arc::new(cache),
+            refresher: Arc::new(Once::new()),
+        }
+    }
+
+    // cache returns the Cache of the handle, for queries which don't
+    // touch the file system.
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    // blocking runs f with the Cache on the blocking thread pool.
+    async fn blocking<F, T>(&self, f: F) -> Result<T, CdiError>
+    where
+        F: FnOnce(&Cache) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let cache = self.cache.clone();
+        tokio::task::spawn_blocking(move || f(&cache))
+            .await
+            .map_err(|err| anyhow::Error::new(err).context("Cache task failed").into())
+    }
+
+    // refresh rescans the Spec directories, see Cache::refresh().
+    pub async fn refresh(&self) -> Result<(), CdiError> {
+        self.blocking(|cache| cache.refresh()).await?
+    }
+
+    // inject_devices injects the given devices into an OCI Spec, see
+    // Cache::inject_devices(). The OCI Spec is only updated on success.
+    pub async fn inject_devices(
+        &self,
+        oci_spec: &mut oci::Spec,
+        devices: Vec<String>,
+    ) -> Result<Vec<String>, CdiError> {
+        let mut spec = oci_spec.clone();
+        let (spec, result) = self
+            .blocking(move |cache| {
+                let result = cache.inject_devices(Some(&mut spec), devices);
+                (spec, result)
+            })
+            .await?;
+        let result = result?;
+        *oci_spec = spec;
+        Ok(result)
+    }
+
+    // list_devices lists the qualified names of all devices, see
+    // Cache::list_devices().
+    pub async fn list_devices(&self) -> Result<Vec<String>, CdiError> {
+        self.blocking(|cache| cache.list_devices()).await
+    }
+
+    // events returns a stream of the change events of the Cache. With
+    // auto-refresh enabled the Cache is refreshed as soon as the watch
+    // of the Spec directories notices a change, without waiting for the
+    // next query. The stream ends when the Cache handle is dropped. It
+    // needs to be called from within a tokio runtime.
+    pub fn events(&self) -> CacheEvents {
+        let (tx, rx) = unbounded_channel();
+        self.cache
+            .add_subscriber(Box::new(move |event| tx.send(event.clone()).is_ok()));
+        self.refresher.call_once(|| self.start_refresher());
+        CacheEvents { rx }
+    }
+
+    // start_refresher starts a task which refreshes the Cache whenever
+    // its watch notices a change. The task holds no reference to the
+    // Cache between refreshes: dropping the Cache drops the watch and
+    // with it the sending end of the notifications, ending the task.
+    fn start_refresher(&self) {
+        let (tx, mut rx) = unbounded_channel();
+        self.cache
+            .watch
+            .listen(Box::new(move || tx.send(()).is_ok()));
+
+        let cache = Arc::downgrade(&self.cache);
+        tokio::spawn(async move {
+            // The first refresh sets up the watch.
+            loop {
+                let Some(cache) = cache.upgrade() else {
+                    return;
+                };
+                let refresh = tokio::task::spawn_blocking(move || {
+                    let _ = cache.refresh_if_required(false);
+                });
+                if refresh.await.is_err() || rx.recv().await.is_none() {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+// CacheEvents is a stream of the change events of a Cache, see
+// AsyncCache::events().
+pub struct CacheEvents {
+    rx: UnboundedReceiver<CacheEvent>,
+}
+
+impl CacheEvents {
+    // next waits for the next event. It returns None once the stream
+    // has ended.
+    pub async fn next(&mut self) -> Option<CacheEvent> {
+        self.rx.recv().await
+    }
+}
+
+impl Stream for CacheEvents {
+    type Item = CacheEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cache::with_auto_refresh,
+        test_utils::{dir_cache, spec_yaml},
+    };
+    use std::{fs, time::Duration};
+
+    #[tokio::test]
+    async fn refresh_query_and_inject() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("vendor.yaml"),
+            spec_yaml("vendor.com/device", "VENDOR=1"),
+        )
+        .unwrap();
+        let mut cache = dir_cache(&[dir.path().to_str().unwrap()]);
+        with_auto_refresh(false)(&mut cache);
+        let cache = AsyncCache::new(cache);
+
+        cache.refresh().await.unwrap();
+        assert_eq!(
+            cache.list_devices().await.unwrap(),
+            vec!["vendor.com/device=gpu0"]
+        );
+
+        let mut oci_spec = oci::Spec::default();
+        let injected = cache
+            .inject_devices(&mut oci_spec, vec!["vendor.com/device=gpu0".into()])
+            .await
+            .unwrap();
+        assert_eq!(injected, vec!["vendor.com/device=gpu0"]);
+        let env = oci_spec.process().as_ref().unwrap().env().clone().unwrap();
+        assert!(env.contains(&"VENDOR=1".to_string()));
+
+        let mut oci_spec = oci::Spec::default();
+        let err = cache
+            .inject_devices(&mut oci_spec, vec!["vendor.com/device=gpu1".into()])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, CdiError::UnresolvableDevice(_)));
+        assert_eq!(oci_spec, oci::Spec::default());
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore = "miri does not support inotify")]
+    async fn events_follow_the_spec_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = dir_cache(&[dir.path().to_str().unwrap()]);
+        with_auto_refresh(true)(&mut cache);
+        let cache = AsyncCache::new(cache);
+        let mut streams = vec![cache.events(), cache.clone().events()];
+
+        fs::write(
+            dir.path().join("vendor.yaml"),
+            spec_yaml("vendor.com/device", "VENDOR=1"),
+        )
+        .unwrap();
+        for events in &mut streams {
+            let event = loop {
+                let event = tokio::time::timeout(Duration::from_secs(5), events.next())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                if !event.added.is_empty() {
+                    break event;
+                }
+            };
+            assert_eq!(event.added, vec!["vendor.com/device=gpu0"]);
+        }
+
+        drop(cache);
+        let end = tokio::time::timeout(Duration::from_secs(5), async {
+            for events in &mut streams {
+                while events.next().await.is_some() {}
+            }
+        });
+        end.await.unwrap();
+    }
+}
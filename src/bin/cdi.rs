@@ -1,21 +1,28 @@
 extern crate container_device_interface as cdi;
 mod cdi_ops;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
 
 use cdi_ops::{
     args::{CdiCli, Commands},
-    handler::{handle_cdi_devices, handle_cdi_inject},
+    handler::{handle_cdi_classes, handle_cdi_devices, handle_cdi_inject},
 };
 
 fn main() -> Result<()> {
     let cli = CdiCli::parse();
 
+    if let Some(err) = cdi::default_cache::get_config_error() {
+        return Err(err).context("failed to load the CDI configuration");
+    }
+
     match &cli.command {
         Commands::Devices(args) => {
             handle_cdi_devices(args)?;
         }
+        Commands::Classes => {
+            handle_cdi_classes()?;
+        }
         Commands::Inject(args) => {
             handle_cdi_inject(args)?;
         } // TODO: to support more command here
@@ -1,3 +1,5 @@
+use std::collections::{BTreeMap, BTreeSet};
+
 use anyhow::Result;
 use cdi::default_cache::get_default_cache;
 use cdi::default_cache::{inject_devices, list_devices};
@@ -13,10 +15,8 @@ pub fn cdi_inject_devices(
     patterns: Vec<String>,
     format: &str,
 ) -> Result<()> {
-    let devices = find_target_devices(list_devices(), patterns);
-    if let Err(unresolved) = inject_devices(oci_spec, devices) {
-        println!("{:?}", unresolved.to_string());
-    }
+    let devices = find_target_devices(list_devices(), patterns)?;
+    inject_devices(oci_spec, devices)?;
 
     println!("Updated OCI Spec:");
     print!("{}", marshal_object(2, oci_spec, format));
@@ -26,7 +26,9 @@ pub fn cdi_inject_devices(
 
 pub fn cdi_list_devices(verbose: bool, format: &str) -> Result<()> {
     let cache = get_default_cache();
-    let devices = cache.lock().unwrap().list_devices();
+    let cache = cache.read().unwrap();
+    // Take all devices from the same Snapshot of the Cache.
+    let devices: Vec<Device> = cache.iter_devices().map(|(_, dev)| dev).collect();
 
     if devices.is_empty() {
         println!("No CDI devices found");
@@ -34,13 +36,42 @@ pub fn cdi_list_devices(verbose: bool, format: &str) -> Result<()> {
     }
 
     println!("CDI devices found:");
-    for (idx, device) in devices.iter().enumerate() {
-        cdi_print_device(
+    for (idx, dev) in devices.into_iter().enumerate() {
+        cdi_print_device(idx, dev, verbose, format, 2);
+    }
+    Ok(())
+}
+
+pub fn cdi_list_classes() -> Result<()> {
+    let cache = get_default_cache();
+    let cache = cache.read().unwrap();
+
+    // Collect the classes and their vendors in one pass over the devices
+    // of the same Snapshot of the Cache.
+    let mut vendors: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for (_, dev) in cache.iter_devices() {
+        let spec = dev.get_spec();
+        vendors
+            .entry(spec.get_class())
+            .or_default()
+            .insert(spec.get_vendor());
+    }
+
+    if vendors.is_empty() {
+        println!("No CDI device classes found.");
+        return Ok(());
+    }
+
+    println!("CDI device classes found:");
+    for (idx, (class, class_vendors)) in vendors.iter().enumerate() {
+        let class_vendors: Vec<&str> = class_vendors.iter().map(String::as_str).collect();
+        println!(
+            "{}{}. {} ({} vendors: {})",
+            indent(2),
             idx,
-            cache.lock().unwrap().get_device(device).unwrap().clone(),
-            verbose,
-            format,
-            2,
+            class,
+            class_vendors.len(),
+            class_vendors.join(", ")
         );
     }
     Ok(())
@@ -93,6 +124,7 @@ mod tests {
         .unwrap();
         refresh().unwrap();
         cdi_list_devices(false, " ").unwrap();
+        cdi_list_classes().unwrap();
 
         let dir = tempfile::tempdir().unwrap();
         fs::write(
@@ -119,25 +151,36 @@ devices:
 
         // Non-verbose and verbose (the latter prints the global spec edits).
         cdi_list_devices(false, " ").unwrap();
+        cdi_list_classes().unwrap();
         cdi_list_devices(true, "json").unwrap();
         cdi_list_devices(true, "").unwrap();
 
+        // Unknown devices fail the injection, like patterns matching none.
         let mut oci_spec = oci::Spec::default();
-        cdi_inject_devices(
+        let err = cdi_inject_devices(
             &mut oci_spec,
             vec![
                 "vendor.com/device=gpu0".to_string(),
-                "vendor.com/device=unknown".to_string(), // filtered, not an error
+                "vendor.com/device=unknown".to_string(),
             ],
             "yaml",
         )
+        .unwrap_err();
+        assert!(err.to_string().contains("vendor.com/device=unknown"));
+        assert_eq!(oci_spec, oci::Spec::default());
+
+        cdi_inject_devices(
+            &mut oci_spec,
+            vec!["vendor.com/device=gpu0".to_string()],
+            "yaml",
+        )
         .unwrap();
         let env = oci_spec.process().as_ref().unwrap().env().as_ref().unwrap();
         assert!(env.contains(&"VENDOR=1".to_string()));
         assert!(env.contains(&"GLOBAL=1".to_string()));
 
         // A loadable spec whose device node cannot be stat'ed fails at
-        // inject time (fill_missing_info) - the error-print path.
+        // inject time (fill_missing_info), leaving the OCI Spec alone.
         fs::write(
             dir.path().join("broken.yaml"),
             r#"cdiVersion: "0.6.0"
@@ -152,11 +195,16 @@ devices:
         .unwrap();
         refresh().unwrap();
         let mut oci_spec = oci::Spec::default();
-        cdi_inject_devices(
+        let err = cdi_inject_devices(
             &mut oci_spec,
             vec!["vendor2.com/device=bad0".to_string()],
             "yaml",
         )
-        .unwrap();
+        .unwrap_err();
+        assert!(
+            format!("{err:?}").contains("/nonexistent/device/node"),
+            "{err:?}"
+        );
+        assert_eq!(oci_spec, oci::Spec::default());
     }
 }
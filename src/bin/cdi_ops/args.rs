@@ -10,6 +10,14 @@ Spec files, vendors, classes, devices, validating the content
 of the registry, injecting devices into OCI Specs, and for
 monitoring changes in the Registry.
 
+The registry is configured by
+/etc/container-device-interface/config.yaml, or the file set with
+CDI_CONFIG_FILE, and by the CDI_SPEC_DIRS, CDI_ROOTLESS,
+CDI_AUTO_REFRESH, CDI_CONFLICT_POLICY and CDI_SECURE_SPEC_FILES
+environment variables. With CDI_ROOTLESS=true and no Spec directories
+set, non-root users also get the Spec directories under
+$XDG_CONFIG_HOME and $XDG_RUNTIME_DIR.
+
 See cdi --help for a list of available commands. You can get
 additional help about <command> by using 'cdi <command> -h'.
 "
@@ -29,6 +37,14 @@ pub enum Commands {
     )]
     Devices(DevicesArgs),
 
+    /// List device classes in the CDI registry
+    #[clap(
+        about = "List CDI device classes.",
+        long_about = "The 'classes' command lists all CDI device classes known to the registry,
+together with the vendors providing devices of each class."
+    )]
+    Classes,
+
     /// Inject CDI devices into an OCI Spec.
     #[clap(
         about = "Inject CDI devices into an OCI Spec.",
@@ -1,6 +1,8 @@
 use anyhow::{Context, Result};
 
-use crate::cdi_ops::{api::cdi_inject_devices, api::cdi_list_devices, utils::read_oci_spec};
+use crate::cdi_ops::{
+    api::cdi_inject_devices, api::cdi_list_classes, api::cdi_list_devices, utils::read_oci_spec,
+};
 
 use super::args::{DevicesArgs, InjectArgs};
 
@@ -16,3 +18,8 @@ pub fn handle_cdi_devices(args: &DevicesArgs) -> Result<()> {
     cdi_list_devices(args.verbose, &args.format).context("cdi list devices failed")?;
     Ok(())
 }
+
+pub fn handle_cdi_classes() -> Result<()> {
+    cdi_list_classes().context("cdi list classes failed")?;
+    Ok(())
+}
@@ -1,23 +1,49 @@
 use std::{collections::HashSet, fs::File, io::BufReader, path::Path};
 
 use anyhow::{anyhow, Context, Result};
+use cdi::{
+    error::CdiError,
+    parser::{is_device_pattern, match_device_pattern},
+};
 use oci_spec::runtime as oci;
 
-pub(crate) fn find_target_devices(devices: Vec<String>, patterns: Vec<String>) -> Vec<String> {
+// find_target_devices returns the sorted list of devices matching any of
+// the given patterns. Patterns are either device names or glob patterns.
+// Unknown device names and glob patterns matching no device are reported
+// together, as a CdiError::UnresolvableDevice.
+pub(crate) fn find_target_devices(
+    devices: Vec<String>,
+    patterns: Vec<String>,
+) -> Result<Vec<String>> {
     let devices_set: HashSet<String> = devices.into_iter().collect();
 
     let mut device_matches: HashSet<String> = HashSet::new();
+    let mut unresolved = Vec::new();
 
     for pattern in patterns {
-        if devices_set.contains(&pattern) {
-            device_matches.insert(pattern);
+        let matches: Vec<&String> = if is_device_pattern(&pattern) {
+            devices_set
+                .iter()
+                .filter(|device| match_device_pattern(&pattern, device))
+                .collect()
+        } else {
+            devices_set.get(&pattern).into_iter().collect()
+        };
+        if matches.is_empty() {
+            unresolved.push(pattern);
+            continue;
         }
+        device_matches.extend(matches.into_iter().cloned());
+    }
+
+    if !unresolved.is_empty() {
+        return Err(CdiError::UnresolvableDevice(unresolved).into());
     }
 
     let mut devices = device_matches.into_iter().collect::<Vec<String>>();
     devices.sort();
 
-    devices
+    Ok(devices)
 }
 
 pub fn read_oci_spec(path: &str) -> Result<oci::Spec> {
@@ -42,10 +68,46 @@ mod tests {
             "device2".to_string(),
             "device3".to_string(),
         ];
+        let patterns = vec!["device3".to_string(), "device2".to_string()];
+
+        let matches = find_target_devices(devices.clone(), patterns).unwrap();
+        assert_eq!(matches, vec!["device2", "device3"]);
+
         let patterns = vec!["device2".to_string(), "device4".to_string()];
+        let err = find_target_devices(devices, patterns).unwrap_err();
+        assert_eq!(err.to_string(), "unresolvable CDI devices device4");
+    }
+
+    #[test]
+    fn find_target_devices_expands_globs() {
+        let devices = vec![
+            "vendor.com/gpu=0".to_string(),
+            "vendor.com/gpu=1".to_string(),
+            "vendor.com/nic=0".to_string(),
+        ];
 
-        let matches = find_target_devices(devices, patterns);
+        let matches = find_target_devices(
+            devices.clone(),
+            vec![
+                "vendor.com/gpu=*".to_string(),
+                "vendor.com/nic=0".to_string(),
+            ],
+        )
+        .unwrap();
+        assert_eq!(matches, devices);
 
-        println!("Matches: {:?}", matches);
+        let err = find_target_devices(
+            devices,
+            vec![
+                "vendor.com/fpga=*".to_string(),
+                "vendor.com/gpu=0".to_string(),
+                "vendor.com/gpu=2".to_string(),
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unresolvable CDI devices vendor.com/fpga=*, vendor.com/gpu=2"
+        );
     }
 }
@@ -0,0 +1,350 @@
+use std::{collections::HashSet, path::Path, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    cache::{Cache, CdiOption, ConflictPolicy, SpecValidator},
+    error::CdiError,
+    limits::Limits,
+    spec::Spec,
+    spec_dirs::{user_spec_dirs, SpecDir, DEFAULT_SPEC_DIRS},
+    spec_source::SpecSource,
+};
+
+// CacheBuilder sets up a Cache. Unlike options, which change a Cache one by
+// one, the builder checks the settings as a whole before the Cache is
+// created, and build() reports the errors of the first refresh.
+//
+//   let cache = Cache::builder()
+//       .spec_dirs(&["/etc/cdi", "/var/run/cdi"])
+//       .conflict_policy(ConflictPolicy::NewestMtime)
+//       .build()?;
+pub struct CacheBuilder {
+    spec_dirs: Vec<SpecDir>,
+    auto_refresh: bool,
+    debounce: Option<Duration>,
+    all_devices: bool,
+    hash_content: bool,
+    conflict_policy: ConflictPolicy,
+    snapshot_file: Option<String>,
+    secure_spec_files: bool,
+    limits: Limits,
+    validators: Vec<SpecValidator>,
+    spec_sources: Vec<Arc<dyn SpecSource>>,
+    options: Vec<CdiOption>,
+}
+
+impl Default for CacheBuilder {
+    // default returns a builder for the default Spec directories, with
+    // auto-refresh enabled.
+    fn default() -> Self {
+        Self {
+            spec_dirs: Vec::new(),
+            auto_refresh: true,
+            debounce: None,
+            all_devices: false,
+            hash_content: false,
+            conflict_policy: ConflictPolicy::default(),
+            snapshot_file: None,
+            secure_spec_files: false,
+            limits: Limits::default(),
+            validators: Vec::new(),
+            spec_sources: Vec::new(),
+            options: Vec::new(),
+        }
+        .spec_dirs(&DEFAULT_SPEC_DIRS)
+    }
+}
+
+impl CacheBuilder {
+    // spec_dirs sets the Spec directories. The directories get their index
+    // in dirs as their priority, like with_spec_dirs.
+    pub fn spec_dirs(mut self, dirs: &[&str]) -> Self {
+        self.spec_dirs = dirs
+            .iter()
+            .enumerate()
+            .map(|(priority, dir)| SpecDir::new(dir, priority as i32))
+            .collect();
+        self
+    }
+
+    // rootless_spec_dirs sets the default Spec directories of the current
+    // user, like with_rootless_spec_dirs.
+    pub fn rootless_spec_dirs(mut self) -> Self {
+        self.spec_dirs = user_spec_dirs();
+        self
+    }
+
+    // spec_dir adds a Spec directory with its own settings, replacing a
+    // Spec directory with the same path, like with_spec_dir.
+    pub fn spec_dir(mut self, dir: SpecDir) -> Self {
+        let dir = dir.cleaned();
+        match self.spec_dirs.iter_mut().find(|d| d.path == dir.path) {
+            Some(existing) => *existing = dir,
+            None => self.spec_dirs.push(dir),
+        }
+        self
+    }
+
+    // auto_refresh enables or disables watching the Spec directories, see
+    // with_auto_refresh.
+    pub fn auto_refresh(mut self, auto_refresh: bool) -> Self {
+        self.auto_refresh = auto_refresh;
+        self
+    }
+
+    // watch_debounce sets how long the watched Spec directories need to
+    // stay quiet after a change before the Cache is refreshed. It needs
+    // auto-refresh.
+    pub fn watch_debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = Some(debounce);
+        self
+    }
+
+    // conflict_policy sets the policy for devices defined by several Specs,
+    // see with_conflict_policy.
+    pub fn conflict_policy(mut self, policy: ConflictPolicy) -> Self {
+        self.conflict_policy = policy;
+        self
+    }
+
+    // validator adds a check for the loaded Specs. Specs failing any of
+    // the checks are not used, their errors are recorded like the errors
+    // of Specs which fail to load.
+    pub fn validator<F>(mut self, validator: F) -> Self
+    where
+        F: Fn(&Spec) -> Result<()> + Send + Sync + 'static,
+    {
+        self.validators.push(Arc::new(validator));
+        self
+    }
+
+    // spec_source adds a SpecSource, see with_spec_source. A Cache with
+    // SpecSources needs no Spec directories.
+    pub fn spec_source<S: SpecSource + 'static>(mut self, source: S) -> Self {
+        self.spec_sources.push(Arc::new(source));
+        self
+    }
+
+    // all_devices enables the synthesized "all" devices, see
+    // with_all_devices.
+    pub fn all_devices(mut self, all_devices: bool) -> Self {
+        self.all_devices = all_devices;
+        self
+    }
+
+    // content_hash enables hashing Spec files to notice changes, see
+    // with_content_hash.
+    pub fn content_hash(mut self, hash_content: bool) -> Self {
+        self.hash_content = hash_content;
+        self
+    }
+
+    // snapshot_file sets the file the loaded Specs are saved to, see
+    // with_snapshot_file. An empty path disables the snapshot file.
+    pub fn snapshot_file(mut self, path: &str) -> Self {
+        self.snapshot_file = (!path.is_empty()).then(|| path.to_owned());
+        self
+    }
+
+    // secure_spec_files enables refusing Spec files unprivileged users
+    // could modify, see with_secure_spec_files.
+    pub fn secure_spec_files(mut self, secure: bool) -> Self {
+        self.secure_spec_files = secure;
+        self
+    }
+
+    // limits sets the limits for loading Spec files, see with_limits.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // options adds options, for instance those of a config::Config. They
+    // are applied after the other settings and checked like them.
+    pub fn options(mut self, options: Vec<CdiOption>) -> Self {
+        self.options.extend(options);
+        self
+    }
+
+    // build checks the settings, creates the Cache and refreshes it. Errors
+    // of the refresh are returned, the Cache is only returned if all Spec
+    // files could be loaded.
+    pub fn build(self) -> Result<Cache, CdiError> {
+        let mut cache = Cache::default();
+        cache.spec_dirs = self.spec_dirs;
+        cache.auto_refresh = self.auto_refresh;
+        cache.all_devices = self.all_devices;
+        cache.hash_content = self.hash_content;
+        cache.conflict_policy = self.conflict_policy;
+        cache.snapshot_file = self.snapshot_file;
+        cache.secure_spec_files = self.secure_spec_files;
+        cache.limits = self.limits;
+        cache.validators = self.validators;
+        cache.spec_sources = self.spec_sources;
+        cache.configure(self.options);
+        validate(&cache, self.debounce)?;
+
+        if let Some(debounce) = self.debounce {
+            cache.watch.get_mut().set_debounce(debounce);
+        }
+        cache.refresh()?;
+        Ok(cache)
+    }
+}
+
+// validate checks the settings of a Cache built by a CacheBuilder.
+fn validate(cache: &Cache, debounce: Option<Duration>) -> Result<(), CdiError> {
+    let invalid =
+        |reason: String| -> CdiError { anyhow!("invalid Cache settings, {}", reason).into() };
+
+    if cache.spec_dirs.is_empty() && cache.spec_sources.is_empty() {
+        return Err(invalid("no Spec directories or sources".to_string()));
+    }
+    let mut paths = HashSet::new();
+    for dir in &cache.spec_dirs {
+        if !Path::new(&dir.path).is_absolute() {
+            return Err(invalid(format!(
+                "Spec directory {:?} is not absolute",
+                dir.path
+            )));
+        }
+        if !paths.insert(&dir.path) {
+            return Err(invalid(format!("Spec directory {} given twice", dir.path)));
+        }
+    }
+
+    if let Some(file) = cache.snapshot_file.as_deref() {
+        if !Path::new(file).is_absolute() {
+            return Err(invalid(format!("snapshot file {:?} is not absolute", file)));
+        }
+    }
+
+    if let Some(debounce) = debounce {
+        if !cache.auto_refresh {
+            return Err(invalid(
+                "watch debounce set without auto-refresh".to_string(),
+            ));
+        }
+        if debounce.is_zero() {
+            return Err(invalid("watch debounce is zero".to_string()));
+        }
+    }
+
+    let limits = &cache.limits;
+    if limits.max_file_size == 0 || limits.max_spec_files == 0 || limits.max_devices == 0 {
+        return Err(invalid(
+            "the file size, Spec file and device limits must not be zero".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{spec_dirs::with_spec_dirs, test_utils::spec_yaml};
+    use std::fs;
+
+    #[test]
+    fn build_refreshes_and_reports_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("vendor.yaml"),
+            spec_yaml("vendor.com/device", "VENDOR=1"),
+        )
+        .unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        let cache = Cache::builder()
+            .spec_dirs(&[path])
+            .auto_refresh(false)
+            .conflict_policy(ConflictPolicy::LastPath)
+            .build()
+            .unwrap();
+        assert_eq!(cache.list_devices(), vec!["vendor.com/device=gpu0"]);
+        assert_eq!(cache.get_spec_dirs()[0].path, path);
+
+        fs::write(dir.path().join("broken.yaml"), "kind: [").unwrap();
+        let err = Cache::builder()
+            .spec_dirs(&[path])
+            .auto_refresh(false)
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(err, CdiError::InvalidSpec { .. }), "{err:?}");
+    }
+
+    #[test]
+    fn validators_reject_specs() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("vendor.yaml"),
+            spec_yaml("vendor.com/device", "VENDOR=1"),
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("other.yaml"),
+            spec_yaml("other.com/device", "VENDOR=1"),
+        )
+        .unwrap();
+
+        let builder = || {
+            Cache::builder()
+                .spec_dirs(&[dir.path().to_str().unwrap()])
+                .auto_refresh(false)
+                .validator(|spec: &Spec| {
+                    if spec.get_vendor() != "vendor.com" {
+                        return Err(anyhow!("untrusted vendor {}", spec.get_vendor()));
+                    }
+                    Ok(())
+                })
+        };
+        let err = builder().build().err().unwrap();
+        assert!(format!("{:?}", anyhow::Error::new(err)).contains("untrusted vendor other.com"));
+
+        fs::remove_file(dir.path().join("other.yaml")).unwrap();
+        let cache = builder().build().unwrap();
+        assert_eq!(cache.list_devices(), vec!["vendor.com/device=gpu0"]);
+    }
+
+    #[test]
+    fn rootless_spec_dirs_are_those_of_the_user() {
+        let builder = Cache::builder().rootless_spec_dirs();
+        assert_eq!(builder.spec_dirs, user_spec_dirs());
+        // Like the option, it replaces the Spec directories set before.
+        let builder = Cache::builder()
+            .spec_dirs(&["/opt/cdi"])
+            .rootless_spec_dirs();
+        assert_eq!(builder.spec_dirs, user_spec_dirs());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "miri does not support inotify")]
+    fn build_rejects_invalid_settings() {
+        let invalid = |builder: CacheBuilder| {
+            let err = builder.auto_refresh(false).build().err().unwrap();
+            assert!(err.to_string().contains("invalid Cache settings"), "{err}");
+        };
+
+        invalid(Cache::builder().spec_dirs(&[]));
+        invalid(Cache::builder().spec_dirs(&["cdi"]));
+        invalid(Cache::builder().spec_dirs(&["/etc/cdi", "/etc/cdi/"]));
+        invalid(Cache::builder().snapshot_file("cache"));
+        invalid(Cache::builder().watch_debounce(Duration::from_millis(10)));
+        invalid(Cache::builder().limits(Limits {
+            max_devices: 0,
+            ..Default::default()
+        }));
+        invalid(Cache::builder().options(vec![with_spec_dirs(&["relative"])]));
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::builder()
+            .spec_dirs(&[dir.path().to_str().unwrap()])
+            .watch_debounce(Duration::from_millis(10))
+            .build()
+            .unwrap();
+        assert!(cache.list_devices().is_empty());
+    }
+}
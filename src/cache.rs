@@ -1,52 +1,37 @@
 use std::{
     cell::RefCell,
-    collections::{HashMap, HashSet},
-    error::Error,
-    fmt,
-    sync::{Arc, Mutex},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Receiver},
+        Arc, Mutex, RwLock,
+    },
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use path_clean::clean;
 
 use oci_spec::runtime as oci;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    //watch::Watch,
+    builder::CacheBuilder,
     container_edits::ContainerEdits,
-    device::Device,
-    spec::Spec,
-    spec_dirs::{convert_errors, scan_spec_dirs, with_spec_dirs, SpecError, DEFAULT_SPEC_DIRS},
+    device::{new_device, Device},
+    error::CdiError,
+    limits::Limits,
+    parser::{is_device_pattern, is_qualified_name, match_device_pattern},
+    spec::{new_spec, Spec},
+    spec_dirs::{with_spec_dirs, SpecDir, DEFAULT_SPEC_DIRS},
+    spec_source::{load_spec_sources, DirSpecSource, SpecSource},
+    specs::config::{Device as CDIDevice, Spec as CDISpec},
+    utils::is_cdi_spec,
+    watch::{DirErrors, SharedWatch},
 };
 
-// Define custom errors if not already defined
-#[derive(Debug)]
-struct ConflictError {
-    name: String,
-    dev_path: String,
-    old_path: String,
-}
-
-impl ConflictError {
-    fn new(name: &str, dev_path: &str, old_path: &str) -> Self {
-        Self {
-            name: name.to_owned(),
-            dev_path: dev_path.to_owned(),
-            old_path: old_path.to_owned(),
-        }
-    }
-}
-
-impl fmt::Display for ConflictError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "conflicting device {} (specs {}, {})",
-            self.name, self.dev_path, self.old_path
-        )
-    }
-}
-
-impl Error for ConflictError {}
+// ALL_DEVICE_NAME is the name of the devices synthesized by with_all_devices.
+pub const ALL_DEVICE_NAME: &str = "all";
 
 // CdiOption is an option to change some aspect of default CDI behavior.
 // We define the CdiOption type using a type alias, which is a Box<dyn FnOnce(&mut Cache)>.
@@ -58,35 +43,445 @@ pub type CdiOption = Box<dyn FnOnce(&mut Cache)>;
 // monitored and the Cache is automatically refreshed whenever a change
 // is detected. This option can be used to disable this behavior when a
 // manually refreshed mode is preferable.
+//
+// Changes are detected with inotify and debounced; the refresh itself
+// happens on the next query of the Cache, so an unchanged Cache is not
+// rescanned.
 pub fn with_auto_refresh(auto_refresh: bool) -> CdiOption {
     Box::new(move |c: &mut Cache| {
         c.auto_refresh = auto_refresh;
     })
 }
 
+// with_all_devices returns an option to synthesize an "all" device for
+// every vendor/class kind in the Cache. The "<vendor>/<class>=all" device
+// merges the edits of all devices of its kind and is listed and resolved
+// like any other device. Injecting it injects all devices of the kind. A
+// device named "all" in a Spec always takes precedence. Disabled by default.
+pub fn with_all_devices(all_devices: bool) -> CdiOption {
+    Box::new(move |c: &mut Cache| {
+        c.all_devices = all_devices;
+    })
+}
+
+// with_content_hash returns an option to also hash the content of Spec
+// files when checking them for changes. Refreshing the Cache only reparses
+// Spec files which have changed since the previous refresh. By default a
+// file is considered unchanged if its modification time, size and inode
+// are. Hashing also notices in-place edits which keep all of these, at
+// the cost of reading every Spec file on every refresh.
+pub fn with_content_hash(hash_content: bool) -> CdiOption {
+    Box::new(move |c: &mut Cache| {
+        c.hash_content = hash_content;
+    })
+}
+
+// with_snapshot_file returns an option to save the Specs loaded by the
+// Cache to the given file, for instance DEFAULT_SNAPSHOT_FILE. The first
+// refresh of a Cache restores the saved Specs and only loads Spec files
+// which have changed since they were saved, which avoids validating every
+// Spec again on startup. A missing, corrupt or outdated snapshot file is
+// ignored. An empty path disables the snapshot file.
+pub fn with_snapshot_file(path: &str) -> CdiOption {
+    let path = path.to_owned();
+    Box::new(move |c: &mut Cache| {
+        c.snapshot_file = (!path.is_empty()).then_some(path);
+    })
+}
+
+// with_secure_spec_files returns an option to refuse Spec files which
+// unprivileged users could modify. Specs can add hooks which the runtime
+// runs as root, so in this mode a Spec file and all of its parent dirs
+// must be owned by root or the current user and must not be writable by
+// group or others. Refused files are recorded as CdiError::InsecureSpec
+// errors. A snapshot file is only used if it passes the same checks.
+// Disabled by default.
+pub fn with_secure_spec_files(secure: bool) -> CdiOption {
+    Box::new(move |c: &mut Cache| {
+        c.secure_spec_files = secure;
+    })
+}
+
+// ConflictPolicy decides which device is used when Specs define several
+// devices with the same qualified name. The devices which are not used
+// stay available as shadowed devices, see Cache::get_shadowed_devices().
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ConflictPolicy {
+    // Priority uses the device from the Spec directory with the highest
+    // priority. Devices of Specs with equal priority conflict and none of
+    // them is used.
+    #[default]
+    Priority,
+    // NewestMtime uses the device from the most recently modified Spec
+    // file. Specs of SpecSources have no modification time and count as
+    // the oldest. Ties are broken by priority, then by lexical path order.
+    NewestMtime,
+    // LastPath uses the device from the lexically last Spec file path.
+    LastPath,
+    // Fail makes the refresh fail on any duplicate device. The Cache keeps
+    // the Specs and devices of its last successful refresh.
+    Fail,
+}
+
+impl ConflictPolicy {
+    // resolve compares the conflicting devices. Greater means dev is used,
+    // Less means old is kept and Equal means the devices conflict.
+    fn resolve(&self, dev: &Device, old: &Device) -> std::cmp::Ordering {
+        let (dev, old) = (dev.spec(), old.spec());
+        let priority = || dev.get_priority().cmp(&old.get_priority());
+        let path = || dev.get_path().cmp(&old.get_path());
+        let modified = |spec: &Spec| spec.get_modified();
+
+        match self {
+            ConflictPolicy::Priority => priority(),
+            ConflictPolicy::NewestMtime => modified(dev)
+                .cmp(&modified(old))
+                .then_with(priority)
+                .then_with(path),
+            ConflictPolicy::LastPath => path(),
+            ConflictPolicy::Fail => std::cmp::Ordering::Equal,
+        }
+    }
+}
+
+// with_conflict_policy returns an option to set the policy used to resolve
+// conflicting devices. The default is ConflictPolicy::Priority.
+pub fn with_conflict_policy(policy: ConflictPolicy) -> CdiOption {
+    Box::new(move |c: &mut Cache| {
+        c.conflict_policy = policy;
+    })
+}
+
+// UnresolvedReason tells why a requested device could not be injected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnresolvedReason {
+    // InvalidName means the request is not a qualified device name.
+    InvalidName,
+    // NotFound means no Spec defines the device.
+    NotFound,
+    // NoMatch means the pattern matches no device.
+    NoMatch,
+    // Conflict means several Specs define the device and none is used.
+    Conflict,
+}
+
+impl fmt::Display for UnresolvedReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            UnresolvedReason::InvalidName => "invalid device name",
+            UnresolvedReason::NotFound => "device not found",
+            UnresolvedReason::NoMatch => "no device matches the pattern",
+            UnresolvedReason::Conflict => "conflicting device definitions",
+        };
+        f.write_str(reason)
+    }
+}
+
+// UnresolvedDevice is a device request which could not be injected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnresolvedDevice {
+    pub name: String,
+    pub reason: UnresolvedReason,
+}
+
+// InjectionResult is the result of Cache::inject_available_devices(). It
+// lists the qualified names of the injected devices, with glob patterns
+// expanded, and the requests which could not be injected.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InjectionResult {
+    pub injected: Vec<String>,
+    pub unresolved: Vec<UnresolvedDevice>,
+}
+
+// CacheEvent describes how one refresh changed the devices of a Cache.
+// Devices are listed by their qualified names, in sorted order. A device
+// is changed if its edits, or the global edits, path or priority of its
+// Spec have changed. The spec_paths are the paths of the Specs defining
+// the listed devices, before or after the refresh.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheEvent {
+    pub generation: u64,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+    pub spec_paths: Vec<String>,
+}
+
+impl CacheEvent {
+    // new computes the event for a refresh from the old to the new devices.
+    fn new(generation: u64, old: &HashMap<String, Device>, new: &HashMap<String, Device>) -> Self {
+        let mut event = CacheEvent {
+            generation,
+            ..Default::default()
+        };
+        let mut spec_paths = BTreeSet::new();
+
+        for (name, dev) in new {
+            match old.get(name) {
+                None => event.added.push(name.clone()),
+                Some(old_dev) if !same_device(old_dev, dev) => {
+                    spec_paths.insert(old_dev.spec().get_path());
+                    event.changed.push(name.clone());
+                }
+                Some(_) => continue,
+            }
+            spec_paths.insert(dev.spec().get_path());
+        }
+        for (name, dev) in old {
+            if !new.contains_key(name) {
+                spec_paths.insert(dev.spec().get_path());
+                event.removed.push(name.clone());
+            }
+        }
+
+        event.added.sort();
+        event.removed.sort();
+        event.changed.sort();
+        event.spec_paths = spec_paths.into_iter().collect();
+        event
+    }
+}
+
+// same_device checks if two devices have the same effect when injected.
+fn same_device(a: &Device, b: &Device) -> bool {
+    a.cdi_device == b.cdi_device
+        && a.spec().get_path() == b.spec().get_path()
+        && a.spec().get_priority() == b.spec().get_priority()
+        && a.spec().cdi_spec.container_edits == b.spec().cdi_spec.container_edits
+}
+
+// Snapshot is an immutable view of the Specs and devices found by one
+// refresh of a Cache, together with the errors encountered by it. Queries
+// run against the current Snapshot, while a refresh builds a new one off
+// to the side and swaps it in once complete.
+#[derive(Default)]
+pub(crate) struct Snapshot {
+    pub(crate) specs: HashMap<String, Vec<Spec>>,
+    pub(crate) devices: HashMap<String, Device>,
+    // shadowed keeps the devices which lost a conflict, by qualified name.
+    pub(crate) shadowed: HashMap<String, Vec<Device>>,
+    pub(crate) errors: HashMap<String, Vec<CdiError>>,
+    // synthetic maps the synthesized "all" devices to their member devices.
+    pub(crate) synthetic: HashMap<String, Vec<String>>,
+    // generation is incremented by every refresh.
+    pub(crate) generation: u64,
+}
+
+impl Snapshot {
+    // kind_devices returns the devices of the Snapshot, keyed by their
+    // qualified name, together with their vendor/class kind.
+    fn kind_devices(&self) -> BTreeMap<String, (String, &Device)> {
+        let mut devices = BTreeMap::new();
+        for spec in self.specs.values().flatten() {
+            let kind = format!("{}/{}", spec.get_vendor(), spec.get_class());
+            for dev in spec.devices.values() {
+                let name = dev.get_qualified_name();
+                // Only devices which survived conflict resolution.
+                if let Some(dev) = self.devices.get(&name) {
+                    devices.insert(name, (kind.clone(), dev));
+                }
+            }
+        }
+        for name in self.synthetic.keys() {
+            if let Some(dev) = self.devices.get(name) {
+                let spec = dev.get_spec();
+                let kind = format!("{}/{}", spec.get_vendor(), spec.get_class());
+                devices.insert(name.clone(), (kind, dev));
+            }
+        }
+        devices
+    }
+
+    // synthesize_all_devices adds a "<vendor>/<class>=all" device for every
+    // vendor/class kind, unless a Spec already defines one. Its edits are
+    // the edits of all the devices of the kind, merged in name order.
+    fn synthesize_all_devices(&mut self) {
+        let mut kinds: BTreeMap<String, Vec<(String, &Device)>> = BTreeMap::new();
+        for (name, (kind, dev)) in self.kind_devices() {
+            kinds.entry(kind).or_default().push((name, dev));
+        }
+
+        let mut synthesized = Vec::new();
+        for (kind, members) in kinds {
+            let name = format!("{}={}", kind, ALL_DEVICE_NAME);
+            if self.devices.contains_key(&name) {
+                continue;
+            }
+
+            let mut edits = ContainerEdits::new();
+            let merged: Result<()> = members
+                .iter()
+                .try_for_each(|(_, dev)| edits.append(dev.edits()));
+            if merged.is_err() {
+                continue;
+            }
+            let cdi_device = CDIDevice {
+                name: ALL_DEVICE_NAME.to_string(),
+                container_edits: edits.container_edits,
+                ..Default::default()
+            };
+            // The device is tied to the Spec of its first member.
+            if let Ok(dev) = new_device(&members[0].1.get_spec(), &cdi_device) {
+                let members = members.into_iter().map(|(name, _)| name).collect();
+                synthesized.push((name, dev, members));
+            }
+        }
+
+        for (name, dev, members) in synthesized {
+            self.devices.insert(name.clone(), dev);
+            self.synthetic.insert(name, members);
+        }
+    }
+
+    // resolve_requests collects the edits of the requested devices and of
+    // their Specs. Requests which can't be resolved are reported in the
+    // result, they don't contribute any edits.
+    pub(crate) fn resolve_requests(
+        &self,
+        requests: Vec<String>,
+    ) -> Result<(ContainerEdits, InjectionResult), CdiError> {
+        let mut edits = ContainerEdits::new();
+        let mut specs: HashSet<&Spec> = HashSet::new();
+        let mut result = InjectionResult::default();
+
+        // Glob patterns which match no device are unresolvable too.
+        let (devices, unmatched) = self.expand_requests(requests);
+        for name in unmatched {
+            result.unresolved.push(UnresolvedDevice {
+                name,
+                reason: UnresolvedReason::NoMatch,
+            });
+        }
+
+        for name in devices {
+            let Some(dev) = self.devices.get(&name) else {
+                let reason = if !is_qualified_name(&name) {
+                    UnresolvedReason::InvalidName
+                } else if self.shadowed.contains_key(&name) {
+                    UnresolvedReason::Conflict
+                } else {
+                    UnresolvedReason::NotFound
+                };
+                result.unresolved.push(UnresolvedDevice { name, reason });
+                continue;
+            };
+
+            // spec.edits may be none when we only have dev.edits
+            // allow dev.edits to be added even if spec.edits is None
+            if specs.insert(dev.spec()) {
+                if let Some(ce) = dev.get_spec().edits() {
+                    edits.append(ce)?
+                }
+            }
+            edits.append(dev.edits())?;
+            result.injected.push(name);
+        }
+
+        Ok((edits, result))
+    }
+
+    // expand_requests expands the glob patterns among the given device
+    // requests into the names of the matching devices, sorted by name.
+    // Other requests are kept as they are. Duplicates are dropped, keeping
+    // the first occurrence. Patterns which match no device are returned
+    // separately.
+    fn expand_requests(&self, requests: Vec<String>) -> (Vec<String>, Vec<String>) {
+        let mut expanded = Vec::new();
+        let mut unmatched = Vec::new();
+        let mut seen = HashSet::new();
+
+        // Synthesized devices are replaced by their members.
+        let mut push = |name: &String| {
+            let members = match self.synthetic.get(name) {
+                Some(members) => members.as_slice(),
+                None => std::slice::from_ref(name),
+            };
+            for name in members {
+                if seen.insert(name.clone()) {
+                    expanded.push(name.clone());
+                }
+            }
+        };
+
+        for request in requests {
+            if !is_device_pattern(&request) {
+                push(&request);
+                continue;
+            }
+
+            let mut matches: Vec<&String> = self
+                .devices
+                .keys()
+                .filter(|name| match_device_pattern(&request, name))
+                .collect();
+            if matches.is_empty() {
+                unmatched.push(request);
+                continue;
+            }
+            matches.sort();
+            for name in matches {
+                push(name);
+            }
+        }
+
+        (expanded, unmatched)
+    }
+}
+
+// SpecValidator is an additional check for the Specs loaded by a Cache,
+// see CacheBuilder::validator(). A Spec which fails it is not used.
+pub type SpecValidator = Arc<dyn Fn(&Spec) -> Result<()> + Send + Sync>;
+
+// Cache stores CDI Specs loaded from Spec directories. Queries and device
+// injection take &self and can run in parallel, also with a refresh. A
+// Cache is set up with a CacheBuilder, or with options, see new_cache().
 #[allow(dead_code)]
 #[derive(Default)]
 pub struct Cache {
-    pub spec_dirs: Vec<String>,
-    pub specs: HashMap<String, Vec<Spec>>,
-    pub devices: HashMap<String, Device>,
-    pub errors: HashMap<String, Vec<Box<dyn std::error::Error + Send + Sync + 'static>>>,
-    pub dir_errors: HashMap<String, Box<dyn std::error::Error + Send + Sync + 'static>>,
-
-    pub auto_refresh: bool,
-    //watch: Watch,
+    pub(crate) spec_dirs: Vec<SpecDir>,
+    pub(crate) auto_refresh: bool,
+    pub(crate) all_devices: bool,
+    pub(crate) hash_content: bool,
+    pub(crate) conflict_policy: ConflictPolicy,
+    pub(crate) snapshot_file: Option<String>,
+    pub(crate) secure_spec_files: bool,
+    pub(crate) limits: Limits,
+    pub(crate) validators: Vec<SpecValidator>,
+    // spec_sources provide Specs in addition to the Spec directories.
+    pub(crate) spec_sources: Vec<Arc<dyn SpecSource>>,
+    // ignored_files are never loaded as Spec files, even if they are in a
+    // Spec directory, like the configuration file of the Cache.
+    pub(crate) ignored_files: Vec<PathBuf>,
+
+    snapshot: RwLock<Arc<Snapshot>>,
+    dir_errors: Mutex<DirErrors>,
+    pub(crate) watch: SharedWatch,
+    // dir_source loads the Spec files of the Spec directories.
+    dir_source: DirSpecSource,
+    // refreshing serializes refreshes, so a slower refresh can't replace
+    // the Snapshot of a later one.
+    refreshing: Mutex<()>,
+    subscribers: Mutex<Vec<Subscriber>>,
 }
 
-pub fn new_cache(options: Vec<CdiOption>) -> Arc<Mutex<Cache>> {
-    let cache = Arc::new(Mutex::new(Cache::default()));
+// Subscriber is called with the CacheEvent of every refresh. It returns
+// false once it is no longer interested.
+pub(crate) type Subscriber = Box<dyn Fn(&CacheEvent) -> bool + Send>;
+
+// new_cache creates a Cache for the default Spec directories, configured
+// with the given options. Errors of the initial refresh are only recorded,
+// use CacheBuilder to get them.
+pub fn new_cache(options: Vec<CdiOption>) -> Arc<RwLock<Cache>> {
+    let cache = Arc::new(RwLock::new(Cache::default()));
 
     {
-        let mut c = cache.lock().unwrap();
+        let mut c = cache.write().unwrap();
 
         with_spec_dirs(&DEFAULT_SPEC_DIRS)(&mut c);
         c.configure(options);
         let _ = c.refresh();
-    } // MutexGuard `c` is dropped here
+    } // RwLockWriteGuard `c` is dropped here
 
     cache
 }
@@ -98,98 +493,223 @@ impl Cache {
         devices: HashMap<String, Device>,
     ) -> Self {
         Self {
-            spec_dirs,
-            specs,
-            devices,
-            errors: HashMap::new(),
-            dir_errors: HashMap::new(),
-            auto_refresh: false,
-            //watch: Watch::new(),
+            spec_dirs: spec_dirs
+                .iter()
+                .enumerate()
+                .map(|(priority, dir)| SpecDir::new(dir, priority as i32))
+                .collect(),
+            snapshot: RwLock::new(Arc::new(Snapshot {
+                specs,
+                devices,
+                ..Default::default()
+            })),
+            ..Default::default()
         }
     }
 
+    // builder returns a CacheBuilder for the default Spec directories.
+    pub fn builder() -> CacheBuilder {
+        CacheBuilder::default()
+    }
+
     pub fn configure(&mut self, options: Vec<CdiOption>) {
         for option in options {
             option(self);
         }
+
+        // The Watch is (re)started for the new Spec dirs on the next query.
+        let watch = self.watch.get_mut();
+        if !self.auto_refresh || !watch.is_watching(&self.spec_dirs, &self.limits) {
+            watch.stop();
+        }
+    }
+
+    // snapshot returns the current Snapshot of the Cache. It stays valid
+    // and unchanged, even if the Cache is refreshed meanwhile.
+    pub(crate) fn snapshot(&self) -> Arc<Snapshot> {
+        self.snapshot.read().unwrap().clone()
     }
 
-    pub fn get_device(&mut self, dev_name: &str) -> Option<&Device> {
+    pub fn get_device(&self, dev_name: &str) -> Option<Device> {
         let _ = self.refresh_if_required(false);
 
-        self.devices.get(dev_name)
+        self.snapshot().devices.get(dev_name).cloned()
     }
 
-    pub fn list_devices(&mut self) -> Vec<String> {
+    pub fn list_devices(&self) -> Vec<String> {
         let _ = self.refresh_if_required(false);
 
-        let mut devices: Vec<String> = self.devices.keys().cloned().collect();
+        let mut devices: Vec<String> = self.snapshot().devices.keys().cloned().collect();
         devices.sort();
         devices
     }
 
-    pub fn list_vendors(&mut self) -> Vec<String> {
-        let mut vendors: Vec<String> = Vec::new();
-
+    pub fn list_vendors(&self) -> Vec<String> {
         let _ = self.refresh_if_required(false);
 
-        for vendor in self.specs.keys() {
-            vendors.push(vendor.clone());
-        }
+        let mut vendors: Vec<String> = self.snapshot().specs.keys().cloned().collect();
         vendors.sort();
         vendors
     }
 
-    pub fn get_vendor_specs(&mut self, vendor: &str) -> Vec<Spec> {
+    pub fn get_vendor_specs(&self, vendor: &str) -> Vec<Spec> {
         let _ = self.refresh_if_required(false);
 
-        match self.specs.get(vendor) {
+        match self.snapshot().specs.get(vendor) {
             Some(specs) => specs.clone(),
             None => Vec::new(),
         }
     }
 
-    // refresh the Cache by rescanning CDI Spec directories and files.
-    pub fn refresh(&mut self) -> Result<(), Box<dyn Error>> {
+    // subscribe returns a channel receiving a CacheEvent for every refresh
+    // of the Cache, starting with the next one. The subscription ends when
+    // the receiver is dropped.
+    pub fn subscribe(&self) -> Receiver<CacheEvent> {
+        let (tx, rx) = channel();
+        self.add_subscriber(Box::new(move |event| tx.send(event.clone()).is_ok()));
+        rx
+    }
+
+    // add_subscriber adds a subscriber for the CacheEvents of the Cache.
+    pub(crate) fn add_subscriber(&self, subscriber: Subscriber) {
+        self.subscribers.lock().unwrap().push(subscriber);
+    }
+
+    // generation returns the number of refreshes of the Cache so far. It
+    // matches the generation of the CacheEvent sent for the last refresh.
+    pub fn generation(&self) -> u64 {
+        self.snapshot().generation
+    }
+
+    // list_shadowed_devices lists the qualified names of all devices which
+    // are defined more than once and lost a conflict.
+    pub fn list_shadowed_devices(&self) -> Vec<String> {
+        let _ = self.refresh_if_required(false);
+
+        let mut devices: Vec<String> = self.snapshot().shadowed.keys().cloned().collect();
+        devices.sort();
+        devices
+    }
+
+    // get_shadowed_devices returns the devices with the given qualified name
+    // which lost a conflict, in the order their Specs were scanned.
+    pub fn get_shadowed_devices(&self, dev_name: &str) -> Vec<Device> {
+        let _ = self.refresh_if_required(false);
+
+        self.snapshot()
+            .shadowed
+            .get(dev_name)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // list_classes lists all device classes known to the Cache.
+    pub fn list_classes(&self) -> Vec<String> {
+        let _ = self.refresh_if_required(false);
+
+        let classes: BTreeSet<String> = self
+            .snapshot()
+            .specs
+            .values()
+            .flatten()
+            .map(|spec| spec.get_class())
+            .collect();
+        classes.into_iter().collect()
+    }
+
+    // list_devices_for_kind lists the qualified names of all devices of
+    // the given vendor and class.
+    pub fn list_devices_for_kind(&self, vendor: &str, class: &str) -> Vec<String> {
+        let _ = self.refresh_if_required(false);
+
+        let kind = format!("{}/{}", vendor, class);
+        self.snapshot()
+            .kind_devices()
+            .into_iter()
+            .filter(|(_, (k, _))| *k == kind)
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    // iter_devices returns an iterator over all devices known to the Cache,
+    // together with their vendor/class kind, ordered by qualified device
+    // name. The iterator works on the Snapshot current at the time of the
+    // call and is not affected by later refreshes.
+    pub fn iter_devices(&self) -> impl Iterator<Item = (String, Device)> {
+        let _ = self.refresh_if_required(false);
+
+        let snapshot = self.snapshot();
+        let devices: Vec<(String, Device)> = snapshot
+            .kind_devices()
+            .into_values()
+            .map(|(kind, dev)| (kind, dev.clone()))
+            .collect();
+        devices.into_iter()
+    }
+
+    // refresh the Cache by rescanning CDI Spec directories and files, and
+    // by loading its SpecSources. Errors are recorded against the Spec
+    // files they are for and can be queried with get_errors() and
+    // get_spec_errors(). Queries keep using the previous Snapshot until
+    // the refresh is complete.
+    pub fn refresh(&self) -> Result<(), CdiError> {
+        let _refreshing = self.refreshing.lock().unwrap();
+
         let mut specs: HashMap<String, Vec<Spec>> = HashMap::new();
         let mut devices: HashMap<String, Device> = HashMap::new();
         let mut conflicts: HashSet<String> = HashSet::new();
-        let mut spec_errors: HashMap<String, Vec<Box<dyn Error>>> = HashMap::new();
+        let mut shadowed: HashMap<String, Vec<Device>> = HashMap::new();
+        let mut spec_errors: HashMap<String, Vec<CdiError>> = HashMap::new();
+        let mut errors: Vec<CdiError> = Vec::new();
 
         // Wrap collect_error and resolve_conflict in RefCell
-        let collect_error = RefCell::new(|err: Box<dyn Error>, paths: Vec<String>| {
-            let err_string = err.to_string();
+        let collect_error = RefCell::new(|err: CdiError, paths: Vec<String>| {
             for path in paths {
-                spec_errors
-                    .entry(path.to_string())
-                    .or_default()
-                    .push(Box::new(SpecError::new(&err_string.to_string())));
+                spec_errors.entry(path).or_default().push(err.clone());
             }
+            errors.push(err);
         });
 
+        // resolve_conflict returns true if dev loses against old. Losing
+        // devices are kept as shadowed devices.
         let resolve_conflict = RefCell::new(|name: &str, dev: &Device, old: &Device| -> bool {
-            let dev_spec = dev.get_spec();
-            let old_spec = old.get_spec();
-            let dev_prio = dev_spec.get_priority();
-            let old_prio = old_spec.get_priority();
-
-            match dev_prio.cmp(&old_prio) {
-                std::cmp::Ordering::Greater => false,
+            match self.conflict_policy.resolve(dev, old) {
+                std::cmp::Ordering::Greater => {
+                    shadowed
+                        .entry(name.to_owned())
+                        .or_default()
+                        .push(old.clone());
+                    false
+                }
                 std::cmp::Ordering::Equal => {
-                    let dev_path = dev_spec.get_path();
-                    let old_path = old_spec.get_path();
+                    let dev_path = dev.spec().get_path();
+                    let old_path = old.spec().get_path();
                     collect_error.borrow_mut()(
-                        Box::new(ConflictError::new(name, &dev_path, &old_path)),
+                        CdiError::Conflict {
+                            name: name.to_owned(),
+                            dev_path: dev_path.clone(),
+                            old_path: old_path.clone(),
+                        },
                         vec![dev_path.clone(), old_path.clone()],
                     );
                     conflicts.insert(name.to_owned());
+                    shadowed
+                        .entry(name.to_owned())
+                        .or_default()
+                        .push(dev.clone());
+                    true
+                }
+                std::cmp::Ordering::Less => {
+                    shadowed
+                        .entry(name.to_owned())
+                        .or_default()
+                        .push(dev.clone());
                     true
                 }
-                std::cmp::Ordering::Less => true,
             }
         });
 
-        let mut scan_spec_fn = |s: Spec| -> Result<(), Box<dyn Error>> {
+        let mut scan_spec_fn = |s: Spec| -> Result<(), CdiError> {
             let vendor = s.get_vendor().to_owned();
             specs.entry(vendor.clone()).or_default().push(s.clone());
             let spec_devices = s.get_devices();
@@ -206,38 +726,100 @@ impl Cache {
             Ok(())
         };
 
-        let scaned_specs: Vec<Spec> = scan_spec_dirs(&self.spec_dirs)?;
+        // Specs which fail to load are skipped, the rest is still used.
+        let (mut scaned_specs, mut scan_errors) = self.dir_source.scan(&self.spec_dirs, |files| {
+            files.hash_content = self.hash_content;
+            files.secure = self.secure_spec_files;
+            files.limits = self.limits;
+            files.ignored.clone_from(&self.ignored_files);
+            files.snapshot_file = self.snapshot_file.as_ref().map(PathBuf::from);
+        });
+        let (source_specs, source_errors) = load_spec_sources(&self.spec_sources, &self.limits);
+        scaned_specs.extend(source_specs);
+        scan_errors.extend(source_errors);
+        for err in scan_errors {
+            let path = err.get_path().unwrap_or_default().to_owned();
+            collect_error.borrow_mut()(err, vec![path]);
+        }
         for spec in scaned_specs {
+            let path = spec.get_path();
+            let rejected = self
+                .validators
+                .iter()
+                .find_map(|validate| validate(&spec).err());
+            if let Some(err) = rejected {
+                let err = CdiError::invalid_spec(Path::new(&path), err.context("rejected Spec"));
+                collect_error.borrow_mut()(err, vec![path]);
+                continue;
+            }
             scan_spec_fn(spec)?
         }
 
+        let previous = self.snapshot();
+
+        // Failing the refresh keeps the previous Specs and devices, only
+        // the errors are updated.
+        if self.conflict_policy == ConflictPolicy::Fail && !conflicts.is_empty() {
+            *self.snapshot.write().unwrap() = Arc::new(Snapshot {
+                specs: previous.specs.clone(),
+                devices: previous.devices.clone(),
+                shadowed: previous.shadowed.clone(),
+                errors: spec_errors,
+                synthetic: previous.synthetic.clone(),
+                generation: previous.generation,
+            });
+            return Err(CdiError::from_errors(errors));
+        }
+
         for conflict in conflicts.iter() {
-            devices.remove(conflict);
+            if let Some(dev) = devices.remove(conflict) {
+                shadowed.entry(conflict.clone()).or_default().push(dev);
+            }
         }
 
-        self.specs = specs;
-        self.devices = devices;
-        self.errors = convert_errors(&spec_errors);
+        let mut snapshot = Snapshot {
+            specs,
+            devices,
+            shadowed,
+            errors: spec_errors,
+            generation: previous.generation + 1,
+            ..Default::default()
+        };
+        if self.all_devices {
+            snapshot.synthesize_all_devices();
+        }
+        let event = CacheEvent::new(snapshot.generation, &previous.devices, &snapshot.devices);
+        *self.snapshot.write().unwrap() = Arc::new(snapshot);
 
-        let errs: Vec<String> = spec_errors
-            .values()
-            .flat_map(|errors| errors.iter().map(|err| err.to_string()))
-            .collect();
+        // Subscribers which have gone away are dropped.
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber(&event));
 
-        if !errs.is_empty() {
-            Err(errs.join(", ").into())
+        if !errors.is_empty() {
+            Err(CdiError::from_errors(errors))
         } else {
             Ok(())
         }
     }
 
-    fn refresh_if_required(&mut self, force: bool) -> Result<bool, Box<dyn std::error::Error>> {
+    pub(crate) fn refresh_if_required(&self, force: bool) -> Result<bool, CdiError> {
         // We need to refresh if
         // - it's forced by an explicit call to Refresh() in manual mode
+        // - the watch is (re)started for the Spec dirs in auto-refresh mode
         // - a missing Spec dir appears (added to watch) in auto-refresh mode
-        // TODO: Here it will be recoverd if watch is completed.
-        // if force || (self.auto_refresh && self.watch.update(&mut self.dir_errors, vec![])) {
-        if force || (self.auto_refresh) {
+        // - a Spec file has changed in auto-refresh mode
+        // Only the last three need the Watch to be locked, see
+        // SharedWatch::check.
+        let update = self.watch.check(
+            self.auto_refresh,
+            &self.spec_dirs,
+            &self.limits,
+            &self.dir_errors,
+        ) || force;
+
+        if update {
             self.refresh()?;
             return Ok(true);
         }
@@ -245,53 +827,209 @@ impl Cache {
         Ok(false)
     }
 
+    // inject_devices injects the given qualified devices into an OCI Spec.
+    // Device requests may also be glob patterns, like "vendor.com/gpu=*",
+    // see match_device_pattern(). They are expanded to all matching devices
+    // in the order of their names. Unknown devices and patterns matching no
+    // device make the injection fail. The qualified names of the injected
+    // devices are returned.
     pub fn inject_devices(
-        &mut self,
+        &self,
         oci_spec: Option<&mut oci::Spec>,
         devices: Vec<String>,
-    ) -> Result<Vec<String>, Box<dyn Error + Send + Sync + 'static>> {
-        let mut unresolved = Vec::new();
+    ) -> Result<Vec<String>, CdiError> {
+        let oci_spec = match oci_spec {
+            Some(spec) => spec,
+            None => return Err(CdiError::EmptyOciSpec),
+        };
+
+        let _ = self.refresh_if_required(false);
+
+        // Resolve all devices against the same Snapshot.
+        let (mut edits, result) = self.snapshot().resolve_requests(devices)?;
+
+        if !result.unresolved.is_empty() {
+            let unresolved = result.unresolved.into_iter().map(|u| u.name).collect();
+            return Err(CdiError::UnresolvableDevice(unresolved));
+        }
 
+        edits.apply(oci_spec)?;
+
+        Ok(result.injected)
+    }
+
+    // inject_available_devices is the best-effort variant of inject_devices.
+    // It injects every device which can be resolved and reports the other
+    // requests, with the reason, in the result instead of failing. Only an
+    // empty OCI Spec or failing to apply the edits is an error. The OCI
+    // Spec is left alone if no device can be injected.
+    pub fn inject_available_devices(
+        &self,
+        oci_spec: Option<&mut oci::Spec>,
+        devices: Vec<String>,
+    ) -> Result<InjectionResult, CdiError> {
         let oci_spec = match oci_spec {
             Some(spec) => spec,
-            None => return Err("can't inject devices, OCI Spec is empty".into()),
+            None => return Err(CdiError::EmptyOciSpec),
         };
 
         let _ = self.refresh_if_required(false);
 
-        let edits = &mut ContainerEdits::new();
-        let mut specs: HashSet<Spec> = HashSet::new();
-
-        for device in devices {
-            if let Some(dev) = self.devices.get(&device) {
-                let mut spec = dev.get_spec();
-                if specs.insert(spec.clone()) {
-                    // spec.edits may be none when we only have dev.edits
-                    // allow dev.edits to be added even if spec.edits is None
-                    if let Some(ce) = spec.edits() {
-                        edits.append(ce)?
-                    }
-                }
-                edits.append(dev.edits())?;
-            } else {
-                unresolved.push(device);
+        let (mut edits, result) = self.snapshot().resolve_requests(devices)?;
+        if result.injected.is_empty() {
+            return Ok(result);
+        }
+
+        edits.apply(oci_spec)?;
+
+        Ok(result)
+    }
+
+    // write_spec writes a Spec file with the given content into the highest
+    // priority writable Spec directory. If name has a "json" or "yaml"
+    // extension it choses the encoding. Otherwise the default YAML encoding
+    // is used. The Spec is validated before it is written and the Cache is
+    // refreshed afterwards, so its devices are available on return.
+    //
+    // Only Spec directories with the writable setting are used. If the
+    // Spec file can't be written to the directory, for instance because
+    // it is on a read-only file system, the writable directory with the
+    // next highest priority is tried.
+    pub fn write_spec(&self, raw: &CDISpec, name: &str) -> Result<(), CdiError> {
+        let dirs = self.writable_spec_dirs();
+        if dirs.is_empty() {
+            return Err(anyhow!("no writable Spec directory to write to").into());
+        }
+
+        let mut errors = Vec::new();
+        for (dir, priority) in dirs {
+            let path = spec_file_path(&dir, name)?;
+            let spec = new_spec(raw, &path, priority)?;
+            if let Err(err) = spec.write(true) {
+                errors.push(CdiError::spec_file(&path, err));
+                continue;
+            }
+
+            // Errors of other Specs are recorded by the refresh; they don't
+            // make the write itself fail.
+            let _ = self.refresh();
+            return Ok(());
+        }
+
+        Err(CdiError::from_errors(errors))
+    }
+
+    // remove_spec removes a Spec with the given name from the writable Spec
+    // directories. This function can be used to remove a Spec previously
+    // written by write_spec(), whichever directory it was written to.
+    // Removing a Spec which does not exist is not an error. The Cache is
+    // refreshed, so the devices of the Spec are gone on return.
+    pub fn remove_spec(&self, name: &str) -> Result<(), CdiError> {
+        let dirs = self.writable_spec_dirs();
+        if dirs.is_empty() {
+            return Err(anyhow!("no writable Spec directory to remove from").into());
+        }
+
+        let mut removed = false;
+        let mut errors = Vec::new();
+        for (dir, _) in dirs {
+            let path = spec_file_path(&dir, name)?;
+            match fs::remove_file(&path) {
+                Ok(()) => removed = true,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::NotFound | std::io::ErrorKind::NotADirectory
+                    ) => {}
+                Err(e) => errors.push(CdiError::spec_file(
+                    &path,
+                    anyhow::Error::from(e).context("failed to remove Spec file"),
+                )),
             }
         }
 
-        if !unresolved.is_empty() {
-            return Err(format!("unresolvable CDI devices {}", unresolved.join(", ")).into());
+        if removed {
+            let _ = self.refresh();
         }
+        if !errors.is_empty() {
+            return Err(CdiError::from_errors(errors));
+        }
+        Ok(())
+    }
+
+    // writable_spec_dirs returns the Spec directories with the writable
+    // setting, together with their priority, highest priority first. Of
+    // several directories with the same priority, the last one comes first.
+    fn writable_spec_dirs(&self) -> Vec<(String, i32)> {
+        let mut dirs: Vec<(usize, &SpecDir)> = self
+            .spec_dirs
+            .iter()
+            .enumerate()
+            .filter(|(_, dir)| dir.writable)
+            .collect();
+        dirs.sort_by_key(|(index, dir)| std::cmp::Reverse((dir.priority, *index)));
+        dirs.into_iter()
+            .map(|(_, dir)| (dir.path.clone(), dir.priority))
+            .collect()
+    }
+
+    // get_spec_dirs returns the Spec directories of the Cache.
+    pub fn get_spec_dirs(&self) -> Vec<SpecDir> {
+        self.spec_dirs.clone()
+    }
 
-        if let Err(err) = edits.apply(oci_spec) {
-            return Err(format!("failed to inject devices: {}", err).into());
+    // get_errors returns all errors encountered during the last Cache
+    // refresh, keyed by the path of the Spec file they are for, together
+    // with the errors of the Spec directories, keyed by directory path.
+    pub fn get_errors(&self) -> HashMap<String, Vec<CdiError>> {
+        let mut errors = self.snapshot().errors.clone();
+        for (dir, err) in self.get_dir_errors() {
+            errors.entry(dir).or_default().push(err);
         }
+        errors
+    }
+
+    // get_spec_errors returns the errors encountered for the Spec file
+    // with the given path during the last Cache refresh.
+    pub fn get_spec_errors(&self, path: &str) -> Vec<CdiError> {
+        let path = clean(path).display().to_string();
+        self.snapshot()
+            .errors
+            .get(&path)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // get_dir_errors returns the errors encountered for the Spec
+    // directories, for instance failing to monitor them for changes.
+    pub fn get_dir_errors(&self) -> HashMap<String, CdiError> {
+        self.dir_errors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(dir, err)| (dir.clone(), CdiError::Other(err.clone())))
+            .collect()
+    }
+}
 
-        Ok(Vec::new())
+// spec_file_path returns the path of the Spec file with the given name in
+// dir, see spec_file_name.
+fn spec_file_path(dir: &str, name: &str) -> Result<PathBuf> {
+    Ok(Path::new(dir).join(spec_file_name(name)?))
+}
+
+// spec_file_name checks the name of a Spec file given to write_spec() or
+// remove_spec(). Names without a "json" or "yaml" extension get the default
+// "yaml" extension appended.
+pub(crate) fn spec_file_name(name: &str) -> Result<String> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(anyhow!("invalid Spec file name {:?}", name));
     }
 
-    pub fn get_errors(&self) -> HashMap<String, Vec<anyhow::Error>> {
-        // Return errors if any
-        HashMap::new()
+    if is_cdi_spec(Path::new(name)) {
+        Ok(name.to_owned())
+    } else {
+        Ok(format!("{}.yaml", name))
     }
 }
 
@@ -305,28 +1043,17 @@ mod tests {
             ContainerEdits as CDIContainerEdits, Device as CDIDevice, DeviceNode, IntelRdt,
             Spec as CDISpec,
         },
+        test_utils::{dir_cache, raw_spec, spec_yaml},
     };
     use oci_spec::runtime::Spec as OCISpec;
-    use std::{collections::HashMap, fs, path::PathBuf};
-
-    fn spec_yaml(kind: &str, env: &str) -> String {
-        format!(
-            r#"cdiVersion: "0.6.0"
-kind: "{kind}"
-devices:
-  - name: "gpu0"
-    containerEdits:
-      env:
-        - "{env}"
-"#
-        )
-    }
-
-    fn dir_cache(dirs: &[&str]) -> Cache {
-        let mut cache = Cache::default();
-        with_spec_dirs(dirs)(&mut cache);
-        cache
-    }
+    use std::{
+        collections::HashMap,
+        error::Error,
+        fs,
+        path::PathBuf,
+        thread,
+        time::{Duration, Instant},
+    };
 
     #[test]
     fn refresh_scans_dirs_and_answers_queries() {
@@ -336,7 +1063,7 @@ devices:
             spec_yaml("vendor.com/device", "VENDOR=1"),
         )
         .unwrap();
-        let mut cache = dir_cache(&[dir.path().to_str().unwrap()]);
+        let cache = dir_cache(&[dir.path().to_str().unwrap()]);
 
         cache.refresh().unwrap();
 
@@ -349,42 +1076,363 @@ devices:
     }
 
     #[test]
-    fn auto_refresh_picks_up_new_specs_without_manual_refresh() {
+    fn queries_run_in_parallel_with_refreshes() {
         let dir = tempfile::tempdir().unwrap();
-        let mut cache = dir_cache(&[dir.path().to_str().unwrap()]);
-        with_auto_refresh(true)(&mut cache);
-        assert!(cache.list_devices().is_empty());
-
         fs::write(
             dir.path().join("vendor.yaml"),
             spec_yaml("vendor.com/device", "VENDOR=1"),
         )
         .unwrap();
+        let cache = dir_cache(&[dir.path().to_str().unwrap()]);
+        cache.refresh().unwrap();
+        let before = cache.snapshot();
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..10 {
+                        let mut oci_spec = OCISpec::default();
+                        cache
+                            .inject_devices(
+                                Some(&mut oci_spec),
+                                vec!["vendor.com/device=gpu0".to_string()],
+                            )
+                            .unwrap();
+                    }
+                });
+            }
+            s.spawn(|| {
+                for _ in 0..10 {
+                    cache.refresh().unwrap();
+                }
+            });
+        });
 
-        // No explicit refresh(): the query must trigger it.
+        // Refreshes swap in a new Snapshot, old ones stay untouched.
+        assert!(!Arc::ptr_eq(&before, &cache.snapshot()));
+        assert_eq!(before.devices.len(), 1);
         assert_eq!(cache.list_devices(), vec!["vendor.com/device=gpu0"]);
     }
 
     #[test]
-    fn later_dir_wins_on_conflicting_device_names() {
-        let low = tempfile::tempdir().unwrap();
-        let high = tempfile::tempdir().unwrap();
-        fs::write(
-            low.path().join("a.yaml"),
-            spec_yaml("vendor.com/device", "FROM=low"),
-        )
-        .unwrap();
-        fs::write(
-            high.path().join("b.yaml"),
-            spec_yaml("vendor.com/device", "FROM=high"),
-        )
-        .unwrap();
-        let mut cache = dir_cache(&[low.path().to_str().unwrap(), high.path().to_str().unwrap()]);
-
+    fn class_queries_group_devices_by_kind() {
+        let dir = tempfile::tempdir().unwrap();
+        for (file, kind) in [
+            ("a.yaml", "vendor.com/gpu"),
+            ("b.yaml", "vendor.com/nic"),
+            ("c.yaml", "other.com/gpu"),
+        ] {
+            fs::write(dir.path().join(file), spec_yaml(kind, "VENDOR=1")).unwrap();
+        }
+        let cache = dir_cache(&[dir.path().to_str().unwrap()]);
+        cache.refresh().unwrap();
+
+        assert_eq!(cache.list_classes(), vec!["gpu", "nic"]);
+        assert_eq!(
+            cache.list_devices_for_kind("vendor.com", "gpu"),
+            vec!["vendor.com/gpu=gpu0"]
+        );
+        assert!(cache.list_devices_for_kind("vendor.com", "fpga").is_empty());
+
+        let devices: Vec<(String, String)> = cache
+            .iter_devices()
+            .map(|(kind, dev)| (kind, dev.get_qualified_name()))
+            .collect();
+        assert_eq!(
+            devices,
+            vec![
+                (
+                    "other.com/gpu".to_string(),
+                    "other.com/gpu=gpu0".to_string()
+                ),
+                (
+                    "vendor.com/gpu".to_string(),
+                    "vendor.com/gpu=gpu0".to_string()
+                ),
+                (
+                    "vendor.com/nic".to_string(),
+                    "vendor.com/nic=gpu0".to_string()
+                ),
+            ]
+        );
+    }
+
+    // wait_for_devices polls the Cache until the watch has picked up changes.
+    fn wait_for_devices(cache: &Cache, expected: &[&str]) -> Vec<String> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let devices = cache.list_devices();
+            if devices == expected || Instant::now() > deadline {
+                return devices;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "miri does not support inotify")]
+    fn auto_refresh_picks_up_new_specs_without_manual_refresh() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = dir_cache(&[dir.path().to_str().unwrap()]);
+        with_auto_refresh(true)(&mut cache);
+        assert!(cache.list_devices().is_empty());
+
+        fs::write(
+            dir.path().join("vendor.yaml"),
+            spec_yaml("vendor.com/device", "VENDOR=1"),
+        )
+        .unwrap();
+
+        // No explicit refresh(): the query must trigger it.
+        assert_eq!(
+            wait_for_devices(&cache, &["vendor.com/device=gpu0"]),
+            vec!["vendor.com/device=gpu0"]
+        );
+
+        fs::remove_file(dir.path().join("vendor.yaml")).unwrap();
+        assert!(wait_for_devices(&cache, &[]).is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "miri does not support inotify")]
+    fn auto_refresh_does_not_rescan_unchanged_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = dir_cache(&[dir.path().to_str().unwrap()]);
+        with_auto_refresh(true)(&mut cache);
+        assert!(cache.list_devices().is_empty());
+
+        // Bypass the watch: without a change event the Cache stays as is.
+        let mut devices = HashMap::new();
+        devices.insert("vendor.com/device=fake".to_string(), Device::default());
+        *cache.snapshot.write().unwrap() = Arc::new(Snapshot {
+            devices,
+            ..Default::default()
+        });
+        assert_eq!(cache.list_devices(), vec!["vendor.com/device=fake"]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "miri does not support inotify")]
+    fn spec_dirs_created_after_setup_are_watched() {
+        let parent = tempfile::tempdir().unwrap();
+        let dir = parent.path().join("cdi");
+        let mut cache = dir_cache(&[dir.to_str().unwrap()]);
+        with_auto_refresh(true)(&mut cache);
+        assert!(cache.list_devices().is_empty());
+        let dir_errors = cache.get_dir_errors();
+        assert!(dir_errors[dir.to_str().unwrap()]
+            .to_string()
+            .contains("failed to monitor for changes"));
+        assert!(cache.get_errors().contains_key(dir.to_str().unwrap()));
+
+        fs::create_dir(&dir).unwrap();
+        fs::write(
+            dir.join("vendor.yaml"),
+            spec_yaml("vendor.com/device", "VENDOR=1"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            wait_for_devices(&cache, &["vendor.com/device=gpu0"]),
+            vec!["vendor.com/device=gpu0"]
+        );
+        assert!(cache.get_dir_errors().is_empty());
+    }
+
+    #[test]
+    fn later_dir_wins_on_conflicting_device_names() {
+        let low = tempfile::tempdir().unwrap();
+        let high = tempfile::tempdir().unwrap();
+        fs::write(
+            low.path().join("a.yaml"),
+            spec_yaml("vendor.com/device", "FROM=low"),
+        )
+        .unwrap();
+        fs::write(
+            high.path().join("b.yaml"),
+            spec_yaml("vendor.com/device", "FROM=high"),
+        )
+        .unwrap();
+        let cache = dir_cache(&[low.path().to_str().unwrap(), high.path().to_str().unwrap()]);
+
         cache.refresh().unwrap();
 
         let dev = cache.get_device("vendor.com/device=gpu0").unwrap();
         assert_eq!(dev.get_spec().get_priority(), 1);
+
+        // The losing device can still be inspected.
+        assert_eq!(
+            cache.list_shadowed_devices(),
+            vec!["vendor.com/device=gpu0"]
+        );
+        let shadowed = cache.get_shadowed_devices("vendor.com/device=gpu0");
+        assert_eq!(shadowed.len(), 1);
+        assert_eq!(shadowed[0].get_spec().get_priority(), 0);
+    }
+
+    // conflict_cache returns a Cache with a.yaml and b.yaml both defining
+    // vendor.com/device=gpu0, with a.yaml modified last.
+    fn conflict_cache(dir: &tempfile::TempDir, policy: ConflictPolicy) -> Cache {
+        for (name, env) in [("b.yaml", "FROM=b"), ("a.yaml", "FROM=a")] {
+            let path = dir.path().join(name);
+            fs::write(&path, spec_yaml("vendor.com/device", env)).unwrap();
+            let age = if name == "a.yaml" { 0 } else { 60 };
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(std::time::SystemTime::now() - Duration::from_secs(age))
+                .unwrap();
+        }
+        let mut cache = dir_cache(&[dir.path().to_str().unwrap()]);
+        with_conflict_policy(policy)(&mut cache);
+        cache
+    }
+
+    #[test]
+    fn snapshot_file_is_saved_and_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        let run = tempfile::tempdir().unwrap();
+        let snapshot = run.path().join(".cache");
+        fs::write(
+            dir.path().join("vendor.yaml"),
+            spec_yaml("vendor.com/device", "FROM=spec"),
+        )
+        .unwrap();
+
+        let mut cache = dir_cache(&[dir.path().to_str().unwrap()]);
+        with_snapshot_file(snapshot.to_str().unwrap())(&mut cache);
+        cache.refresh().unwrap();
+        assert!(snapshot.exists());
+
+        let mut restored = dir_cache(&[dir.path().to_str().unwrap()]);
+        with_snapshot_file(snapshot.to_str().unwrap())(&mut restored);
+        restored.refresh().unwrap();
+        assert_eq!(restored.list_devices(), cache.list_devices());
+
+        let mut disabled = dir_cache(&[dir.path().to_str().unwrap()]);
+        with_snapshot_file("")(&mut disabled);
+        assert_eq!(disabled.snapshot_file, None);
+    }
+
+    #[test]
+    fn secure_spec_files_refuses_writable_specs() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vendor.yaml");
+        fs::write(&path, spec_yaml("vendor.com/device", "VENDOR=1")).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o666)).unwrap();
+
+        // Without the security mode any Spec file is loaded.
+        let cache = dir_cache(&[dir.path().to_str().unwrap()]);
+        cache.refresh().unwrap();
+        assert_eq!(cache.list_devices(), vec!["vendor.com/device=gpu0"]);
+
+        let mut cache = dir_cache(&[dir.path().to_str().unwrap()]);
+        with_secure_spec_files(true)(&mut cache);
+        let err = cache.refresh().unwrap_err();
+        assert!(matches!(err, CdiError::InsecureSpec { .. }), "{err:?}");
+        assert!(err.to_string().contains("is writable by group or others"));
+        assert!(cache.list_devices().is_empty());
+        assert_eq!(cache.get_spec_errors(path.to_str().unwrap()).len(), 1);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        cache.refresh().unwrap();
+        assert_eq!(cache.list_devices(), vec!["vendor.com/device=gpu0"]);
+
+        // The parent directories are checked too.
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o777)).unwrap();
+        let err = cache.refresh().unwrap_err();
+        assert_eq!(err.get_path(), path.to_str());
+        assert!(cache.list_devices().is_empty());
+    }
+
+    #[test]
+    fn refresh_enforces_the_configured_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vendor.yaml");
+        fs::write(&path, spec_yaml("vendor.com/device", "VENDOR=1")).unwrap();
+
+        let mut cache = dir_cache(&[dir.path().to_str().unwrap()]);
+        crate::limits::with_limits(Limits {
+            max_file_size: 32,
+            ..Default::default()
+        })(&mut cache);
+        let err = cache.refresh().unwrap_err();
+        assert!(
+            matches!(err, CdiError::LimitExceeded { max: 32, .. }),
+            "{err:?}"
+        );
+        assert_eq!(err.get_path(), path.to_str());
+        assert!(cache.list_devices().is_empty());
+    }
+
+    fn device_env(cache: &Cache, name: &str) -> Option<Vec<String>> {
+        cache
+            .get_device(name)
+            .and_then(|dev| dev.cdi_device.container_edits.env)
+    }
+
+    #[test]
+    fn conflict_policies_pick_the_device() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = conflict_cache(&dir, ConflictPolicy::LastPath);
+        cache.refresh().unwrap();
+        assert_eq!(
+            device_env(&cache, "vendor.com/device=gpu0"),
+            Some(vec!["FROM=b".to_string()])
+        );
+        assert_eq!(
+            cache.get_shadowed_devices("vendor.com/device=gpu0").len(),
+            1
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache = conflict_cache(&dir, ConflictPolicy::NewestMtime);
+        cache.refresh().unwrap();
+        assert_eq!(
+            device_env(&cache, "vendor.com/device=gpu0"),
+            Some(vec!["FROM=a".to_string()])
+        );
+
+        // Equal priority conflicts drop both devices, but keep them shadowed.
+        let dir = tempfile::tempdir().unwrap();
+        let cache = conflict_cache(&dir, ConflictPolicy::Priority);
+        assert!(cache.refresh().is_err());
+        assert!(cache.get_device("vendor.com/device=gpu0").is_none());
+        assert_eq!(
+            cache.get_shadowed_devices("vendor.com/device=gpu0").len(),
+            2
+        );
+    }
+
+    #[test]
+    fn failing_conflict_policy_keeps_the_previous_devices() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("a.yaml"),
+            spec_yaml("vendor.com/device", "FROM=a"),
+        )
+        .unwrap();
+        let mut cache = dir_cache(&[dir.path().to_str().unwrap()]);
+        with_conflict_policy(ConflictPolicy::Fail)(&mut cache);
+        cache.refresh().unwrap();
+
+        fs::write(
+            dir.path().join("b.yaml"),
+            spec_yaml("vendor.com/device", "FROM=b"),
+        )
+        .unwrap();
+        let err = cache.refresh().unwrap_err();
+
+        assert!(err.to_string().contains("conflicting device"));
+        assert_eq!(cache.get_errors().len(), 2);
+        assert_eq!(cache.generation(), 1);
+        assert_eq!(
+            device_env(&cache, "vendor.com/device=gpu0"),
+            Some(vec!["FROM=a".to_string()])
+        );
     }
 
     #[test]
@@ -400,25 +1448,407 @@ devices:
             spec_yaml("vendor.com/device", "FROM=b"),
         )
         .unwrap();
-        let mut cache = dir_cache(&[dir.path().to_str().unwrap()]);
+        let cache = dir_cache(&[dir.path().to_str().unwrap()]);
 
         let err = cache.refresh().unwrap_err();
 
         assert!(err.to_string().contains("conflicting device"));
-        assert!(!cache.errors.is_empty());
+        assert!(!cache.get_errors().is_empty());
         assert!(cache.get_device("vendor.com/device=gpu0").is_none());
+
+        // The conflict is reported against both Spec files.
+        let errors = cache.get_errors();
+        assert_eq!(errors.len(), 2);
+        for name in ["a.yaml", "b.yaml"] {
+            let path = dir.path().join(name);
+            let errs = cache.get_spec_errors(path.to_str().unwrap());
+            assert_eq!(errs.len(), 1);
+            assert!(errs[0].to_string().contains("conflicting device"));
+        }
     }
 
     #[test]
-    fn inject_devices_requires_an_oci_spec() {
+    fn load_errors_are_reported_with_their_cause() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("broken.yaml");
+        fs::write(&path, "cdiVersion: [not a spec").unwrap();
+        fs::write(
+            dir.path().join("vendor.yaml"),
+            spec_yaml("vendor.com/device", "VENDOR=1"),
+        )
+        .unwrap();
+        let cache = dir_cache(&[dir.path().to_str().unwrap()]);
+
+        // The broken Spec does not hide the devices of the other Spec.
+        let err = cache.refresh().unwrap_err();
+        assert!(matches!(err, CdiError::InvalidSpec { .. }));
+        assert_eq!(err.get_path(), path.to_str());
+        assert_eq!(cache.list_devices(), vec!["vendor.com/device=gpu0"]);
+
+        let errs = cache.get_spec_errors(path.to_str().unwrap());
+        assert_eq!(errs.len(), 1);
+        assert!(errs[0].to_string().contains("invalid CDI Spec"));
+        let cause = errs[0].source().unwrap();
+        assert!(cause.to_string().contains("parse spec file failed"));
+        assert_eq!(cache.get_errors().len(), 1);
+        assert!(cache.get_dir_errors().is_empty());
+
+        fs::remove_file(&path).unwrap();
+        cache.refresh().unwrap();
+        assert!(cache.get_errors().is_empty());
+    }
+
+    #[test]
+    fn write_spec_publishes_into_the_highest_priority_dir() {
+        let low = tempfile::tempdir().unwrap();
+        let high = tempfile::tempdir().unwrap();
+        let cache = dir_cache(&[low.path().to_str().unwrap(), high.path().to_str().unwrap()]);
+
+        cache
+            .write_spec(
+                &raw_spec("vendor.com/device", "VENDOR=1"),
+                "vendor.com-device",
+            )
+            .unwrap();
+
+        let path = high.path().join("vendor.com-device.yaml");
+        assert!(fs::read_to_string(&path).unwrap().starts_with("---\n"));
+        assert!(fs::read_dir(low.path()).unwrap().next().is_none());
+        // Only the Spec file is left behind, no temporary files.
+        assert_eq!(fs::read_dir(high.path()).unwrap().count(), 1);
+
+        let dev = cache.get_device("vendor.com/device=gpu0").unwrap();
+        assert_eq!(dev.get_spec().get_priority(), 1);
+        assert_eq!(dev.get_spec().get_path(), path.to_str().unwrap());
+
+        cache.remove_spec("vendor.com-device").unwrap();
+        assert!(!path.exists());
+        assert!(cache.list_devices().is_empty());
+
+        // Removing it again is not an error.
+        cache.remove_spec("vendor.com-device").unwrap();
+    }
+
+    #[test]
+    fn write_spec_skips_read_only_dirs() {
+        let vendor = tempfile::tempdir().unwrap();
+        let run = tempfile::tempdir().unwrap();
         let mut cache = Cache::default();
+        with_spec_dirs(&[run.path().to_str().unwrap()])(&mut cache);
+        crate::spec_dirs::with_spec_dir(SpecDir {
+            writable: false,
+            ..SpecDir::new(vendor.path().to_str().unwrap(), 10)
+        })(&mut cache);
+
+        cache
+            .write_spec(&raw_spec("vendor.com/device", "VENDOR=1"), "vendor")
+            .unwrap();
+
+        assert!(run.path().join("vendor.yaml").exists());
+        assert!(fs::read_dir(vendor.path()).unwrap().next().is_none());
+        let dev = cache.get_device("vendor.com/device=gpu0").unwrap();
+        assert_eq!(dev.get_spec().get_priority(), 0);
+    }
+
+    #[test]
+    fn write_spec_encodes_json_by_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir_cache(&[dir.path().to_str().unwrap()]);
+
+        cache
+            .write_spec(&raw_spec("vendor.com/device", "VENDOR=1"), "vendor.json")
+            .unwrap();
+
+        let data = fs::read(dir.path().join("vendor.json")).unwrap();
+        let raw: CDISpec = serde_json::from_slice(&data).unwrap();
+        assert_eq!(raw.kind, "vendor.com/device");
+        assert_eq!(cache.list_devices(), vec!["vendor.com/device=gpu0"]);
+    }
+
+    #[test]
+    fn write_spec_creates_missing_dirs() {
+        let parent = tempfile::tempdir().unwrap();
+        let dir = parent.path().join("run").join("cdi");
+        let cache = dir_cache(&[dir.to_str().unwrap()]);
+
+        cache
+            .write_spec(&raw_spec("vendor.com/device", "VENDOR=1"), "vendor")
+            .unwrap();
+
+        assert!(dir.join("vendor.yaml").exists());
+    }
+
+    #[test]
+    fn write_spec_rejects_invalid_specs_and_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir_cache(&[dir.path().to_str().unwrap()]);
+
+        let err = cache
+            .write_spec(&raw_spec("vendor.com/device", "NOEQUALS"), "vendor")
+            .unwrap_err();
+        assert!(matches!(err, CdiError::InvalidSpec { .. }), "{err:?}");
+
+        for name in ["", "..", "../escape", "sub/dir.yaml"] {
+            let err = cache
+                .write_spec(&raw_spec("vendor.com/device", "VENDOR=1"), name)
+                .unwrap_err();
+            assert!(err.to_string().contains("invalid Spec file name"), "{name}");
+        }
+
+        assert!(fs::read_dir(dir.path()).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn write_spec_needs_a_spec_dir() {
+        let cache = Cache::default();
+        let err = cache
+            .write_spec(&raw_spec("vendor.com/device", "VENDOR=1"), "vendor")
+            .unwrap_err();
+        assert!(err.to_string().contains("no writable Spec directory"));
+    }
+
+    #[test]
+    fn write_spec_falls_back_to_dirs_it_can_write() {
+        let low = tempfile::tempdir().unwrap();
+        let parent = tempfile::tempdir().unwrap();
+        // A Spec dir below a regular file can't be created or written.
+        fs::write(parent.path().join("file"), "").unwrap();
+        let broken = parent.path().join("file").join("cdi");
+        let cache = dir_cache(&[low.path().to_str().unwrap(), broken.to_str().unwrap()]);
+
+        cache
+            .write_spec(&raw_spec("vendor.com/device", "VENDOR=1"), "vendor")
+            .unwrap();
+        let dev = cache.get_device("vendor.com/device=gpu0").unwrap();
+        assert_eq!(dev.get_spec().get_priority(), 0);
+        assert!(low.path().join("vendor.yaml").exists());
+
+        cache.remove_spec("vendor").unwrap();
+        assert!(!low.path().join("vendor.yaml").exists());
+        assert!(cache.list_devices().is_empty());
+
+        let cache = dir_cache(&[broken.to_str().unwrap()]);
+        let err = cache
+            .write_spec(&raw_spec("vendor.com/device", "VENDOR=1"), "vendor")
+            .unwrap_err();
+        assert!(matches!(err, CdiError::SpecFile { .. }), "{err:?}");
+        assert_eq!(err.get_path(), broken.join("vendor.yaml").to_str());
+    }
+
+    #[test]
+    fn inject_devices_expands_glob_patterns() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("gpu.yaml"),
+            r#"cdiVersion: "0.6.0"
+kind: "vendor.com/gpu"
+devices:
+  - name: "mig-2g.0"
+    containerEdits:
+      env: ["MIG_2G_0=1"]
+  - name: "mig-1g.1"
+    containerEdits:
+      env: ["MIG_1G_1=1"]
+  - name: "mig-1g.0"
+    containerEdits:
+      env: ["MIG_1G_0=1"]
+"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("nic.yaml"),
+            spec_yaml("vendor.com/nic", "NIC=1"),
+        )
+        .unwrap();
+        let cache = dir_cache(&[dir.path().to_str().unwrap()]);
+        cache.refresh().unwrap();
+
+        let env = |requests: &[&str]| {
+            let mut oci_spec = OCISpec::default();
+            cache
+                .inject_devices(
+                    Some(&mut oci_spec),
+                    requests.iter().map(|r| r.to_string()).collect(),
+                )
+                .unwrap();
+            // Drop the default environment of the OCI Spec.
+            let env = oci_spec.process().as_ref().unwrap().env().clone().unwrap();
+            env.into_iter()
+                .filter(|e| !e.starts_with("PATH=") && !e.starts_with("TERM="))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            env(&["vendor.com/gpu=mig-1g.*"]),
+            vec!["MIG_1G_0=1", "MIG_1G_1=1"]
+        );
+        // Expansions are sorted, exact names keep their place and
+        // duplicates are only injected once.
+        assert_eq!(
+            env(&[
+                "vendor.com/nic=gpu0",
+                "vendor.com/gpu=*",
+                "vendor.com/gpu=mig-1g.0"
+            ]),
+            vec!["NIC=1", "MIG_1G_0=1", "MIG_1G_1=1", "MIG_2G_0=1"]
+        );
+        assert_eq!(env(&["vendor.com/*=*"]).len(), 4);
+
+        let mut oci_spec = OCISpec::default();
+        let injected = cache
+            .inject_devices(
+                Some(&mut oci_spec),
+                vec![
+                    "vendor.com/gpu=mig-1g.*".into(),
+                    "vendor.com/nic=gpu0".into(),
+                ],
+            )
+            .unwrap();
+        assert_eq!(
+            injected,
+            vec![
+                "vendor.com/gpu=mig-1g.0",
+                "vendor.com/gpu=mig-1g.1",
+                "vendor.com/nic=gpu0"
+            ]
+        );
+
+        let mut oci_spec = OCISpec::default();
+        let err = cache
+            .inject_devices(
+                Some(&mut oci_spec),
+                vec!["vendor.com/gpu=*".into(), "vendor.com/fpga=*".into()],
+            )
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("unresolvable CDI devices vendor.com/fpga=*"));
+    }
+
+    #[test]
+    fn all_devices_are_synthesized_per_kind() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("gpu.yaml"),
+            r#"cdiVersion: "0.6.0"
+kind: "vendor.com/gpu"
+containerEdits:
+  env: ["GLOBAL=1"]
+devices:
+  - name: "1"
+    containerEdits:
+      env: ["GPU_1=1"]
+  - name: "0"
+    containerEdits:
+      env: ["GPU_0=1"]
+"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("nic.yaml"),
+            r#"cdiVersion: "0.6.0"
+kind: "vendor.com/nic"
+devices:
+  - name: "0"
+    containerEdits:
+      env: ["NIC_0=1"]
+  - name: "all"
+    containerEdits:
+      env: ["NIC_ALL=1"]
+"#,
+        )
+        .unwrap();
+        let mut cache = dir_cache(&[dir.path().to_str().unwrap()]);
+        cache.refresh().unwrap();
+        assert!(cache.get_device("vendor.com/gpu=all").is_none());
+
+        with_all_devices(true)(&mut cache);
+        cache.refresh().unwrap();
+
+        assert_eq!(
+            cache.list_devices_for_kind("vendor.com", "gpu"),
+            vec!["vendor.com/gpu=0", "vendor.com/gpu=1", "vendor.com/gpu=all"]
+        );
+        let all = cache.get_device("vendor.com/gpu=all").unwrap();
+        assert_eq!(
+            all.cdi_device.container_edits.env,
+            Some(vec!["GPU_0=1".to_string(), "GPU_1=1".to_string()])
+        );
+
+        // The device defined by the Spec wins over a synthesized one.
+        let all = cache.get_device("vendor.com/nic=all").unwrap();
+        assert_eq!(
+            all.cdi_device.container_edits.env,
+            Some(vec!["NIC_ALL=1".to_string()])
+        );
+
+        let mut oci_spec = OCISpec::default();
+        cache
+            .inject_devices(Some(&mut oci_spec), vec!["vendor.com/gpu=all".into()])
+            .unwrap();
+        let env = oci_spec.process().as_ref().unwrap().env().clone().unwrap();
+        for var in ["GLOBAL=1", "GPU_0=1", "GPU_1=1"] {
+            assert_eq!(env.iter().filter(|e| *e == var).count(), 1, "{var}");
+        }
+    }
+
+    #[test]
+    fn subscribers_receive_the_changes_of_every_refresh() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        fs::write(path("a.yaml"), spec_yaml("vendor.com/gpu", "A=1")).unwrap();
+        fs::write(path("b.yaml"), spec_yaml("vendor.com/nic", "B=1")).unwrap();
+        let cache = dir_cache(&[dir.path().to_str().unwrap()]);
+        let events = cache.subscribe();
+
+        cache.refresh().unwrap();
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.generation, 1);
+        assert_eq!(
+            event.added,
+            vec!["vendor.com/gpu=gpu0", "vendor.com/nic=gpu0"]
+        );
+        assert_eq!(event.spec_paths, vec![path("a.yaml"), path("b.yaml")]);
+
+        fs::write(path("a.yaml"), spec_yaml("vendor.com/gpu", "A=22")).unwrap();
+        fs::remove_file(path("b.yaml")).unwrap();
+        fs::write(path("c.yaml"), spec_yaml("vendor.com/fpga", "C=1")).unwrap();
+        cache.refresh().unwrap();
+        let event = events.try_recv().unwrap();
+        assert_eq!(
+            event,
+            CacheEvent {
+                generation: 2,
+                added: vec!["vendor.com/fpga=gpu0".to_string()],
+                removed: vec!["vendor.com/nic=gpu0".to_string()],
+                changed: vec!["vendor.com/gpu=gpu0".to_string()],
+                spec_paths: vec![path("a.yaml"), path("b.yaml"), path("c.yaml")],
+            }
+        );
+
+        // An unchanged refresh still gets an event.
+        cache.refresh().unwrap();
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.generation, 3);
+        assert!(event.added.is_empty() && event.removed.is_empty() && event.changed.is_empty());
+        assert_eq!(cache.generation(), 3);
+
+        drop(events);
+        cache.refresh().unwrap();
+        assert!(cache.subscribers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn inject_devices_requires_an_oci_spec() {
+        let cache = Cache::default();
         let err = cache.inject_devices(None, vec![]).unwrap_err();
+        assert!(matches!(err, CdiError::EmptyOciSpec), "{err:?}");
         assert!(err.to_string().contains("OCI Spec is empty"));
     }
 
     #[test]
     fn inject_devices_reports_unresolvable_devices() {
-        let mut cache = Cache::default();
+        let cache = Cache::default();
         let mut oci_spec = OCISpec::default();
         let err = cache
             .inject_devices(Some(&mut oci_spec), vec!["vendor.com/device=nope".into()])
@@ -427,6 +1857,88 @@ devices:
         assert!(err.to_string().contains("vendor.com/device=nope"));
     }
 
+    #[test]
+    fn inject_devices_reports_missing_host_devices() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("vendor.yaml"),
+            r#"cdiVersion: "0.6.0"
+kind: "vendor.com/device"
+devices:
+  - name: "gpu0"
+    containerEdits:
+      deviceNodes:
+        - path: "/dev/vendor-gpu0"
+          hostPath: "/nonexistent/vendor-gpu0"
+"#,
+        )
+        .unwrap();
+        let cache = dir_cache(&[dir.path().to_str().unwrap()]);
+        cache.refresh().unwrap();
+
+        let mut oci_spec = OCISpec::default();
+        let err = cache
+            .inject_devices(Some(&mut oci_spec), vec!["vendor.com/device=gpu0".into()])
+            .unwrap_err();
+        assert!(matches!(err, CdiError::HostDeviceMissing { .. }), "{err:?}");
+        assert_eq!(err.get_path(), Some("/nonexistent/vendor-gpu0"));
+
+        let err = cache
+            .inject_devices(Some(&mut oci_spec), vec!["vendor.com/device=gpu1".into()])
+            .unwrap_err();
+        assert!(matches!(err, CdiError::UnresolvableDevice(_)), "{err:?}");
+    }
+
+    #[test]
+    fn inject_available_devices_reports_unresolved_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = conflict_cache(&dir, ConflictPolicy::Priority);
+        fs::write(
+            dir.path().join("nic.yaml"),
+            spec_yaml("vendor.com/nic", "NIC=1"),
+        )
+        .unwrap();
+        assert!(cache.refresh().is_err());
+
+        let mut oci_spec = OCISpec::default();
+        let result = cache
+            .inject_available_devices(
+                Some(&mut oci_spec),
+                vec![
+                    "vendor.com/nic=gpu0".into(),
+                    "vendor.com/device=gpu0".into(),
+                    "vendor.com/nic=nope".into(),
+                    "not-a-device".into(),
+                    "vendor.com/gpu=*".into(),
+                ],
+            )
+            .unwrap();
+        assert_eq!(result.injected, vec!["vendor.com/nic=gpu0"]);
+        let unresolved = |name: &str, reason| UnresolvedDevice {
+            name: name.to_string(),
+            reason,
+        };
+        assert_eq!(
+            result.unresolved,
+            vec![
+                unresolved("vendor.com/gpu=*", UnresolvedReason::NoMatch),
+                unresolved("vendor.com/device=gpu0", UnresolvedReason::Conflict),
+                unresolved("vendor.com/nic=nope", UnresolvedReason::NotFound),
+                unresolved("not-a-device", UnresolvedReason::InvalidName),
+            ]
+        );
+        let env = oci_spec.process().as_ref().unwrap().env().clone().unwrap();
+        assert!(env.contains(&"NIC=1".to_string()));
+
+        // Nothing to inject leaves the OCI Spec alone.
+        let mut oci_spec = OCISpec::default();
+        let result = cache
+            .inject_available_devices(Some(&mut oci_spec), vec!["vendor.com/nic=nope".into()])
+            .unwrap();
+        assert!(result.injected.is_empty());
+        assert_eq!(oci_spec, OCISpec::default());
+    }
+
     #[test]
     fn inject_devices_preserves_spec_level_intel_rdt_with_device_edits() {
         let raw = CDISpec {
@@ -459,7 +1971,7 @@ devices:
         let device = spec.get_device("gpu0").unwrap().clone();
         let mut devices = HashMap::new();
         devices.insert(device.get_qualified_name(), device);
-        let mut cache = Cache::new(Vec::new(), HashMap::new(), devices);
+        let cache = Cache::new(Vec::new(), HashMap::new(), devices);
         let mut oci_spec = OCISpec::default();
 
         cache
@@ -0,0 +1,354 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    cache::{
+        with_auto_refresh, with_conflict_policy, with_secure_spec_files, Cache, CdiOption,
+        ConflictPolicy,
+    },
+    limits::{with_limits, Limits},
+    spec_dirs::{with_rootless_spec_dirs, with_spec_dirs},
+};
+
+// DEFAULT_CONFIG_FILE is the configuration file of the default Cache. It
+// is kept out of the Spec directories, which are scanned for any YAML file.
+pub const DEFAULT_CONFIG_FILE: &str = "/etc/container-device-interface/config.yaml";
+
+// CONFIG_FILE_ENV overrides the path of the configuration file.
+pub const CONFIG_FILE_ENV: &str = "CDI_CONFIG_FILE";
+// SPEC_DIRS_ENV overrides the Spec directories, as a colon separated list
+// in increasing order of priority.
+pub const SPEC_DIRS_ENV: &str = "CDI_SPEC_DIRS";
+// ROOTLESS_ENV overrides the choice of the default Spec directories, "true"
+// for those of the current user or "false".
+pub const ROOTLESS_ENV: &str = "CDI_ROOTLESS";
+// AUTO_REFRESH_ENV overrides the refresh mode, "true" or "false".
+pub const AUTO_REFRESH_ENV: &str = "CDI_AUTO_REFRESH";
+// CONFLICT_POLICY_ENV overrides the conflict policy, for instance "lastPath".
+pub const CONFLICT_POLICY_ENV: &str = "CDI_CONFLICT_POLICY";
+// SECURE_SPEC_FILES_ENV overrides the Spec file security mode, "true" or
+// "false".
+pub const SECURE_SPEC_FILES_ENV: &str = "CDI_SECURE_SPEC_FILES";
+
+// Config is the configuration of the default Cache, which lets Spec
+// directories and Cache options be changed without rebuilding binaries.
+// Settings are taken, from lowest to highest precedence, from
+//   - the built-in defaults,
+//   - the configuration file, DEFAULT_CONFIG_FILE or the file set with
+//     the CDI_CONFIG_FILE environment variable,
+//   - the CDI_* environment variables,
+//   - options passed to default_cache::configure().
+// Settings left out keep the value of the previous level. An example
+// configuration file:
+//
+//   specDirs:
+//     - /etc/cdi
+//     - /var/run/cdi
+//   rootless: false
+//   autoRefresh: true
+//   conflictPolicy: priority
+//   secureSpecFiles: true
+//   limits:
+//     maxSpecFiles: 1024
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct Config {
+    // spec_dirs are the Spec directories, in increasing order of priority.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spec_dirs: Option<Vec<String>>,
+    // rootless picks the default Spec directories of the current user, see
+    // with_rootless_spec_dirs. It is ignored if spec_dirs are set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rootless: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auto_refresh: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conflict_policy: Option<ConflictPolicy>,
+    // secure_spec_files and limits set how strictly Spec files are checked
+    // before they are loaded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secure_spec_files: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limits: Option<Limits>,
+    // file is the configuration file the settings were loaded from. It is
+    // not loaded as a Spec file, should it be in a Spec directory.
+    #[serde(skip)]
+    pub file: Option<PathBuf>,
+}
+
+impl Config {
+    // load returns the configuration from the configuration file and the
+    // environment. A missing DEFAULT_CONFIG_FILE is not an error, a missing
+    // file set with CDI_CONFIG_FILE is.
+    pub fn load() -> Result<Self> {
+        Self::load_with(|name| std::env::var(name).ok())
+    }
+
+    // load_with is load, looking up environment variables with env.
+    pub(crate) fn load_with<F>(env: F) -> Result<Self>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let (file, required) = match env(CONFIG_FILE_ENV).filter(|file| !file.is_empty()) {
+            Some(file) => (PathBuf::from(file), true),
+            None => (PathBuf::from(DEFAULT_CONFIG_FILE), false),
+        };
+        let mut config = match Self::read(&file) {
+            Ok(config) => Self {
+                file: Some(file),
+                ..config
+            },
+            Err(err) if !required && is_not_found(&err) => Self::default(),
+            Err(err) => return Err(err),
+        };
+        config.apply_env(env)?;
+        Ok(config)
+    }
+
+    // read reads a configuration file.
+    pub fn read(path: &Path) -> Result<Self> {
+        let read = || -> Result<Self> {
+            let data = fs::read(path)?;
+            if data.iter().all(u8::is_ascii_whitespace) {
+                return Ok(Self::default());
+            }
+            Ok(serde_yaml::from_slice(&data)?)
+        };
+        read().with_context(|| format!("invalid CDI configuration file {}", path.display()))
+    }
+
+    // apply_env overrides the settings with the CDI_* environment variables
+    // which are set and not empty.
+    fn apply_env<F>(&mut self, env: F) -> Result<()>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let var = |name: &str| env(name).filter(|value| !value.is_empty());
+
+        if let Some(dirs) = var(SPEC_DIRS_ENV) {
+            self.spec_dirs = Some(
+                dirs.split(':')
+                    .filter(|dir| !dir.is_empty())
+                    .map(str::to_owned)
+                    .collect(),
+            );
+        }
+        if let Some(value) = var(ROOTLESS_ENV) {
+            self.rootless = Some(parse_env(ROOTLESS_ENV, &value)?);
+        }
+        if let Some(value) = var(AUTO_REFRESH_ENV) {
+            self.auto_refresh = Some(parse_env(AUTO_REFRESH_ENV, &value)?);
+        }
+        if let Some(value) = var(CONFLICT_POLICY_ENV) {
+            self.conflict_policy = Some(parse_env(CONFLICT_POLICY_ENV, &value)?);
+        }
+        if let Some(value) = var(SECURE_SPEC_FILES_ENV) {
+            self.secure_spec_files = Some(parse_env(SECURE_SPEC_FILES_ENV, &value)?);
+        }
+
+        Ok(())
+    }
+
+    // options returns the Cache options for the settings of the
+    // configuration. Settings left out give no option.
+    pub fn options(&self) -> Vec<CdiOption> {
+        let mut options = Vec::new();
+        if let Some(dirs) = &self.spec_dirs {
+            let dirs: Vec<&str> = dirs.iter().map(String::as_str).collect();
+            options.push(with_spec_dirs(&dirs));
+        } else if self.rootless == Some(true) {
+            options.push(with_rootless_spec_dirs());
+        }
+        if let Some(auto_refresh) = self.auto_refresh {
+            options.push(with_auto_refresh(auto_refresh));
+        }
+        if let Some(policy) = self.conflict_policy {
+            options.push(with_conflict_policy(policy));
+        }
+        if let Some(secure) = self.secure_spec_files {
+            options.push(with_secure_spec_files(secure));
+        }
+        if let Some(limits) = self.limits {
+            options.push(with_limits(limits));
+        }
+        if let Some(file) = &self.file {
+            let file = fs::canonicalize(file).unwrap_or_else(|_| file.clone());
+            options.push(Box::new(move |c: &mut Cache| c.ignored_files.push(file)));
+        }
+        options
+    }
+}
+
+// parse_env parses the value of an environment variable like the same
+// setting in a configuration file.
+fn parse_env<T: DeserializeOwned>(name: &str, value: &str) -> Result<T> {
+    serde_yaml::from_str(value).with_context(|| format!("invalid {} value {:?}", name, value))
+}
+
+fn is_not_found(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|err| err.kind() == ErrorKind::NotFound)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cache::Cache,
+        spec_dirs::{user_spec_dirs, SpecDir},
+        test_utils::spec_yaml,
+    };
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn config_file_is_overridden_by_the_environment() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("config.yaml");
+        fs::write(
+            &file,
+            r#"specDirs: ["/etc/cdi", "/var/run/cdi"]
+autoRefresh: false
+conflictPolicy: newestMtime
+limits:
+  maxSpecFiles: 10
+"#,
+        )
+        .unwrap();
+        let file = file.to_str().unwrap();
+
+        let config = Config::load_with(env(&[(CONFIG_FILE_ENV, file)])).unwrap();
+        assert_eq!(
+            config.spec_dirs,
+            Some(vec!["/etc/cdi".to_string(), "/var/run/cdi".to_string()])
+        );
+        assert_eq!(config.conflict_policy, Some(ConflictPolicy::NewestMtime));
+        assert_eq!(config.limits.unwrap().max_spec_files, 10);
+        assert_eq!(
+            config.limits.unwrap().max_depth,
+            Limits::default().max_depth
+        );
+        assert_eq!(config.secure_spec_files, None);
+
+        let config = Config::load_with(env(&[
+            (CONFIG_FILE_ENV, file),
+            (SPEC_DIRS_ENV, "/opt/cdi:/run/cdi"),
+            (AUTO_REFRESH_ENV, "true"),
+            (CONFLICT_POLICY_ENV, "lastPath"),
+            (SECURE_SPEC_FILES_ENV, ""),
+        ]))
+        .unwrap();
+        assert_eq!(
+            config.spec_dirs,
+            Some(vec!["/opt/cdi".to_string(), "/run/cdi".to_string()])
+        );
+        assert_eq!(config.auto_refresh, Some(true));
+        assert_eq!(config.conflict_policy, Some(ConflictPolicy::LastPath));
+        assert_eq!(config.secure_spec_files, None);
+
+        let mut cache = Cache::default();
+        cache.configure(config.options());
+        assert_eq!(cache.spec_dirs[1].path, "/run/cdi");
+        assert_eq!(cache.spec_dirs[1].priority, 1);
+        assert!(cache.auto_refresh);
+        assert_eq!(cache.conflict_policy, ConflictPolicy::LastPath);
+        assert_eq!(cache.limits.max_spec_files, 10);
+    }
+
+    #[test]
+    fn rootless_picks_the_user_spec_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("config.yaml");
+        fs::write(&file, "rootless: true\n").unwrap();
+        let file = file.to_str().unwrap();
+        let spec_dirs = |config: &Config| {
+            let mut cache = Cache::default();
+            cache.configure(config.options());
+            cache.spec_dirs
+        };
+
+        let config = Config::load_with(env(&[(CONFIG_FILE_ENV, file)])).unwrap();
+        assert_eq!(config.rootless, Some(true));
+        assert_eq!(spec_dirs(&config), user_spec_dirs());
+
+        let config =
+            Config::load_with(env(&[(CONFIG_FILE_ENV, file), (ROOTLESS_ENV, "false")])).unwrap();
+        assert_eq!(config.rootless, Some(false));
+        assert_eq!(spec_dirs(&config), Cache::default().spec_dirs);
+
+        // Explicit Spec directories win.
+        let config =
+            Config::load_with(env(&[(CONFIG_FILE_ENV, file), (SPEC_DIRS_ENV, "/opt/cdi")]))
+                .unwrap();
+        assert_eq!(spec_dirs(&config), vec![SpecDir::new("/opt/cdi", 0)]);
+
+        let err = Config::load_with(env(&[(ROOTLESS_ENV, "maybe")])).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("invalid CDI_ROOTLESS value"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn config_file_in_a_spec_dir_is_not_a_spec() {
+        let dir = tempfile::tempdir().unwrap();
+        let spec_dir = dir.path().to_str().unwrap();
+        let file = dir.path().join("config.yaml");
+        fs::write(&file, format!("specDirs: [{spec_dir:?}]\n")).unwrap();
+        fs::write(
+            dir.path().join("vendor.yaml"),
+            spec_yaml("vendor.com/device", "VENDOR=1"),
+        )
+        .unwrap();
+
+        let config = Config::load_with(env(&[(CONFIG_FILE_ENV, file.to_str().unwrap())])).unwrap();
+        assert_eq!(config.file.as_deref(), Some(file.as_path()));
+
+        let mut cache = Cache::default();
+        cache.configure(config.options());
+        cache.refresh().unwrap();
+        assert!(cache.get_errors().is_empty(), "{:?}", cache.get_errors());
+        assert_eq!(cache.list_devices(), vec!["vendor.com/device=gpu0"]);
+    }
+
+    #[test]
+    fn invalid_configuration_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("config.yaml");
+        fs::write(&file, "specDir: /etc/cdi\n").unwrap();
+        let file = file.to_str().unwrap();
+
+        let err = Config::load_with(env(&[(CONFIG_FILE_ENV, file)])).unwrap_err();
+        assert!(err.to_string().contains("invalid CDI configuration file"));
+
+        let missing = dir.path().join("missing.yaml");
+        let err = Config::load_with(env(&[(CONFIG_FILE_ENV, missing.to_str().unwrap())]));
+        assert!(err.is_err());
+
+        fs::write(dir.path().join("empty.yaml"), "\n").unwrap();
+        let empty = dir.path().join("empty.yaml");
+        let err = Config::load_with(env(&[
+            (CONFIG_FILE_ENV, empty.to_str().unwrap()),
+            (AUTO_REFRESH_ENV, "sometimes"),
+        ]))
+        .unwrap_err();
+        assert!(err.to_string().contains("invalid CDI_AUTO_REFRESH value"));
+        assert_eq!(Config::read(&empty).unwrap(), Config::default());
+    }
+}
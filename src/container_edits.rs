@@ -1,10 +1,11 @@
-use std::{collections::HashSet, str::FromStr};
+use std::{collections::HashSet, io, str::FromStr};
 
 use anyhow::{anyhow, Context, Error, Result};
 use oci_spec::runtime::{self as oci, LinuxDeviceType};
 
 use crate::{
     container_edits_unix::{device_info_from_path, DeviceType},
+    error::CdiError,
     generate::config::Generator,
     specs::config::{
         ContainerEdits as CDIContainerEdits, DeviceNode as CDIDeviceNode, Hook as CDIHook,
@@ -55,7 +56,7 @@ impl ContainerEdits {
 
     // apply edits to the given OCI Spec. Updates the OCI Spec in place.
     // Returns an error if the update fails.
-    pub fn apply(&mut self, oci_spec: &mut oci::Spec) -> Result<()> {
+    pub fn apply(&mut self, oci_spec: &mut oci::Spec) -> Result<(), CdiError> {
         let mut spec_gen: Generator = Generator::spec_gen(Some(oci_spec.clone()));
 
         if let Some(envs) = &self.container_edits.env {
@@ -293,7 +294,10 @@ pub struct DeviceNode {
 }
 
 impl DeviceNode {
-    pub fn fill_missing_info(&mut self) -> Result<()> {
+    // fill_missing_info fills in the type and the major and minor numbers
+    // of the device node from the host device. A missing host device is
+    // reported as CdiError::HostDeviceMissing.
+    pub fn fill_missing_info(&mut self) -> Result<(), CdiError> {
         let host_path = self
             .node
             .host_path
@@ -310,7 +314,17 @@ impl DeviceNode {
             }
         }
 
-        let (dev_type, major, minor) = device_info_from_path(host_path)?;
+        let (dev_type, major, minor) = match device_info_from_path(host_path) {
+            Ok(info) => info,
+            Err(err) => {
+                return Err(match err.downcast_ref::<io::Error>() {
+                    Some(e) if e.kind() == io::ErrorKind::NotFound => CdiError::HostDeviceMissing {
+                        path: host_path.to_owned(),
+                    },
+                    _ => err.into(),
+                })
+            }
+        };
         match self.node.r#type.as_deref() {
             None => self.node.r#type = Some(dev_type),
             Some(node_type) if node_type != dev_type => {
@@ -320,7 +334,8 @@ impl DeviceNode {
                     host_path,
                     node_type,
                     dev_type
-                ));
+                )
+                .into());
             }
             _ => {}
         }
@@ -1,30 +1,53 @@
-use anyhow::Result;
 use std::collections::HashMap;
-use std::error::Error;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, RwLock};
 
 use oci_spec::runtime::Spec;
 use once_cell::sync::OnceCell;
 
-use crate::cache::{new_cache, with_auto_refresh, Cache, CdiOption};
+use crate::{
+    cache::{new_cache, with_auto_refresh, Cache, CdiOption, InjectionResult},
+    config::Config,
+    error::CdiError,
+};
 
 // Process-wide: configure() and the query helpers must observe the same
-// instance, and auto-refresh state has to survive across calls.
-static DEFAULT_CACHE: OnceCell<Arc<Mutex<Cache>>> = OnceCell::new();
+// instance, and auto-refresh state has to survive across calls. Only
+// configure() needs write access, queries and injections share the Cache.
+static DEFAULT_CACHE: OnceCell<Arc<RwLock<Cache>>> = OnceCell::new();
+// CONFIG_ERROR is the error loading the configuration of the default Cache.
+static CONFIG_ERROR: OnceCell<Option<CdiError>> = OnceCell::new();
 
-fn get_or_create_default_cache() -> Arc<Mutex<Cache>> {
+fn get_or_create_default_cache() -> Arc<RwLock<Cache>> {
     DEFAULT_CACHE
-        .get_or_init(|| new_cache(vec![with_auto_refresh(true)]))
+        .get_or_init(|| {
+            // An invalid configuration leaves the defaults in place, the
+            // error can be queried with get_config_error().
+            let mut options = vec![with_auto_refresh(true)];
+            match Config::load() {
+                Ok(config) => options.extend(config.options()),
+                Err(err) => {
+                    let _ = CONFIG_ERROR.set(Some(err.into()));
+                }
+            }
+            new_cache(options)
+        })
         .clone()
 }
 
-pub fn get_default_cache() -> Arc<Mutex<Cache>> {
+// get_config_error returns the error loading the configuration of the
+// default Cache, see config::Config. It creates the default Cache.
+pub fn get_config_error() -> Option<CdiError> {
+    get_or_create_default_cache();
+    CONFIG_ERROR.get().cloned().flatten()
+}
+
+pub fn get_default_cache() -> Arc<RwLock<Cache>> {
     get_or_create_default_cache()
 }
 
-pub fn configure(options: Vec<CdiOption>) -> Result<()> {
+pub fn configure(options: Vec<CdiOption>) -> Result<(), CdiError> {
     let cache = get_or_create_default_cache();
-    let mut cache = cache.lock().unwrap();
+    let mut cache = cache.write().unwrap();
     if options.is_empty() {
         return Ok(());
     }
@@ -32,30 +55,36 @@ pub fn configure(options: Vec<CdiOption>) -> Result<()> {
     Ok(())
 }
 
-pub fn refresh() -> Result<(), Box<dyn Error>> {
+pub fn refresh() -> Result<(), CdiError> {
     let cache = get_default_cache();
-    let mut cache = cache.lock().unwrap();
+    let cache = cache.read().unwrap();
     cache.refresh()
 }
 
-pub fn inject_devices(
+pub fn inject_devices(oci_spec: &mut Spec, devices: Vec<String>) -> Result<Vec<String>, CdiError> {
+    let cache = get_default_cache();
+    let cache = cache.read().unwrap();
+    cache.inject_devices(Some(oci_spec), devices)
+}
+
+pub fn inject_available_devices(
     oci_spec: &mut Spec,
     devices: Vec<String>,
-) -> Result<Vec<String>, Box<dyn Error + Send + Sync + 'static>> {
+) -> Result<InjectionResult, CdiError> {
     let cache = get_default_cache();
-    let mut cache = cache.lock().unwrap();
-    cache.inject_devices(Some(oci_spec), devices)
+    let cache = cache.read().unwrap();
+    cache.inject_available_devices(Some(oci_spec), devices)
 }
 
 pub fn list_devices() -> Vec<String> {
     let cache = get_default_cache();
-    let mut cache = cache.lock().unwrap();
+    let cache = cache.read().unwrap();
     cache.list_devices()
 }
 
-pub fn get_errors() -> HashMap<String, Vec<anyhow::Error>> {
+pub fn get_errors() -> HashMap<String, Vec<CdiError>> {
     let cache = get_default_cache();
-    let cache = cache.lock().unwrap();
+    let cache = cache.read().unwrap();
     cache.get_errors()
 }
 
@@ -68,6 +97,7 @@ mod tests {
     // One test covers the whole lifecycle: the cache is a process-wide
     // singleton, so independent tests would race each other's state.
     #[test]
+    #[cfg_attr(miri, ignore = "miri does not support inotify")]
     fn default_cache_is_a_singleton_and_configurable() {
         let first = get_default_cache();
         let second = get_default_cache();
@@ -92,8 +122,8 @@ devices:
 
         configure(vec![with_spec_dirs(&[dir.path().to_str().unwrap()])]).unwrap();
         assert_eq!(
-            first.lock().unwrap().spec_dirs,
-            vec![dir.path().to_str().unwrap().to_string()],
+            first.read().unwrap().spec_dirs[0].path,
+            dir.path().to_str().unwrap(),
             "configure must act on the singleton"
         );
 
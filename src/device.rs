@@ -55,6 +55,16 @@ impl Device {
         self.cdi_spec.clone()
     }
 
+    // spec returns the Spec this device is defined in, without copying it.
+    pub(crate) fn spec(&self) -> &Spec {
+        &self.cdi_spec
+    }
+
+    // spec_mut returns the Spec this device is defined in for updating it.
+    pub(crate) fn spec_mut(&mut self) -> &mut Spec {
+        &mut self.cdi_spec
+    }
+
     // get_qualified_name returns the qualified name for this device.
     pub fn get_qualified_name(&self) -> String {
         qualified_name(
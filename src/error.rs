@@ -0,0 +1,244 @@
+use std::{error::Error, fmt, path::Path, sync::Arc};
+
+use crate::limits::Limit;
+
+// CdiError is the error type of loading Specs, refreshing a Cache and
+// injecting devices. It lets callers tell errors in the Specs apart from
+// errors in the device requests and on the host. Errors are cheap to
+// clone, so the errors recorded by a Cache can be handed out as they are.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum CdiError {
+    // EmptyOciSpec is an injection without an OCI Spec to inject into.
+    EmptyOciSpec,
+    // UnresolvableDevice lists requested devices which no Spec defines.
+    UnresolvableDevice(Vec<String>),
+    // Conflict is a device defined by several Specs, none of which is used.
+    Conflict {
+        name: String,
+        dev_path: String,
+        old_path: String,
+    },
+    // InvalidSpec is a Spec which failed to load or to validate.
+    InvalidSpec {
+        path: String,
+        source: Arc<dyn Error + Send + Sync + 'static>,
+    },
+    // SchemaViolation is a Spec which does not conform to the JSON schema.
+    SchemaViolation {
+        path: String,
+        reason: String,
+    },
+    // VersionTooOld is a Spec which uses features of a later CDI version
+    // than the version it declares.
+    VersionTooOld {
+        path: String,
+        version: String,
+        required: String,
+    },
+    // InsecureSpec is a Spec file which is refused because it, or one of
+    // its parent directories, could be modified by unprivileged users.
+    InsecureSpec {
+        path: String,
+        reason: String,
+    },
+    // LimitExceeded is a Spec file or directory exceeding one of the
+    // Limits for loading Specs.
+    LimitExceeded {
+        path: String,
+        limit: Limit,
+        max: u64,
+    },
+    // SpecFile is a Spec file which could not be written or removed.
+    SpecFile {
+        path: String,
+        source: Arc<dyn Error + Send + Sync + 'static>,
+    },
+    // HostDeviceMissing is a device node which does not exist on the host.
+    HostDeviceMissing {
+        path: String,
+    },
+    // Multiple collects several errors, for instance of a Cache refresh.
+    Multiple(Vec<CdiError>),
+    // Other is any other error.
+    Other(Arc<dyn Error + Send + Sync + 'static>),
+}
+
+impl CdiError {
+    // invalid_spec returns the error for a Spec at the given path which
+    // failed to load. A CdiError among the causes is returned as it is.
+    pub(crate) fn invalid_spec(path: &Path, err: anyhow::Error) -> Self {
+        match find_cdi_error(&err) {
+            Some(err) => err,
+            None => CdiError::InvalidSpec {
+                path: path.display().to_string(),
+                source: shared(err),
+            },
+        }
+    }
+
+    // spec_file returns the error for a Spec file at the given path which
+    // could not be written or removed.
+    pub(crate) fn spec_file(path: &Path, err: anyhow::Error) -> Self {
+        CdiError::SpecFile {
+            path: path.display().to_string(),
+            source: shared(err),
+        }
+    }
+
+    // from_errors returns a single error as it is and several errors as
+    // a CdiError::Multiple.
+    pub(crate) fn from_errors(mut errors: Vec<CdiError>) -> Self {
+        if errors.len() == 1 {
+            return errors.remove(0);
+        }
+        CdiError::Multiple(errors)
+    }
+
+    // get_path returns the path of the Spec or host device the error is
+    // about, if any.
+    pub fn get_path(&self) -> Option<&str> {
+        match self {
+            CdiError::InvalidSpec { path, .. }
+            | CdiError::SchemaViolation { path, .. }
+            | CdiError::VersionTooOld { path, .. }
+            | CdiError::InsecureSpec { path, .. }
+            | CdiError::LimitExceeded { path, .. }
+            | CdiError::SpecFile { path, .. }
+            | CdiError::HostDeviceMissing { path } => Some(path),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CdiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CdiError::EmptyOciSpec => write!(f, "can't inject devices, OCI Spec is empty"),
+            CdiError::UnresolvableDevice(devices) => {
+                write!(f, "unresolvable CDI devices {}", devices.join(", "))
+            }
+            CdiError::Conflict {
+                name,
+                dev_path,
+                old_path,
+            } => write!(
+                f,
+                "conflicting device {} (specs {}, {})",
+                name, dev_path, old_path
+            ),
+            CdiError::InvalidSpec { path, .. } => write!(f, "invalid CDI Spec {}", path),
+            CdiError::SchemaViolation { path, reason } => {
+                write!(f, "invalid CDI Spec {} schema: {}", path, reason)
+            }
+            CdiError::VersionTooOld {
+                path,
+                version,
+                required,
+            } => write!(
+                f,
+                "invalid CDI Spec {}, the spec version must be at least v{}, not v{}",
+                path, required, version
+            ),
+            CdiError::InsecureSpec { path, reason } => {
+                write!(f, "refusing CDI Spec {}: {}", path, reason)
+            }
+            CdiError::LimitExceeded { path, limit, max } => {
+                write!(f, "{} exceeds the limit of {} {}", path, max, limit)
+            }
+            CdiError::SpecFile { path, .. } => {
+                write!(f, "failed to update CDI Spec file {}", path)
+            }
+            CdiError::HostDeviceMissing { path } => write!(f, "host device {} not found", path),
+            CdiError::Multiple(errors) => {
+                for (i, err) in errors.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    // Like anyhow's "{:#}", include the causes of each error.
+                    write!(f, "{}", err)?;
+                    let mut source = err.source();
+                    while let Some(err) = source {
+                        write!(f, ": {}", err)?;
+                        source = err.source();
+                    }
+                }
+                Ok(())
+            }
+            CdiError::Other(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for CdiError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CdiError::InvalidSpec { source, .. } | CdiError::SpecFile { source, .. } => {
+                Some(source.as_ref())
+            }
+            CdiError::Other(err) => err.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<anyhow::Error> for CdiError {
+    fn from(err: anyhow::Error) -> Self {
+        match find_cdi_error(&err) {
+            Some(err) => err,
+            None => CdiError::Other(shared(err)),
+        }
+    }
+}
+
+// find_cdi_error returns the first CdiError among the causes of err.
+fn find_cdi_error(err: &anyhow::Error) -> Option<CdiError> {
+    err.chain()
+        .find_map(|cause| cause.downcast_ref::<CdiError>())
+        .cloned()
+}
+
+fn shared(err: anyhow::Error) -> Arc<dyn Error + Send + Sync + 'static> {
+    let err: Box<dyn Error + Send + Sync + 'static> = err.into();
+    err.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn cdi_errors_are_found_among_the_causes() {
+        let missing = CdiError::HostDeviceMissing {
+            path: "/dev/gpu0".to_string(),
+        };
+        let err = anyhow::Error::new(missing).context("failed to inject devices");
+        let err = CdiError::from(err);
+        assert!(matches!(err, CdiError::HostDeviceMissing { .. }));
+        assert_eq!(err.get_path(), Some("/dev/gpu0"));
+
+        let err = CdiError::from(anyhow!("broken").context("failed"));
+        assert!(matches!(err, CdiError::Other(_)));
+        assert_eq!(err.to_string(), "failed");
+        assert_eq!(err.source().unwrap().to_string(), "broken");
+    }
+
+    #[test]
+    fn invalid_spec_keeps_the_path_and_cause() {
+        let err = CdiError::invalid_spec(Path::new("/etc/cdi/vendor.yaml"), anyhow!("broken"));
+        assert_eq!(err.to_string(), "invalid CDI Spec /etc/cdi/vendor.yaml");
+        assert_eq!(err.get_path(), Some("/etc/cdi/vendor.yaml"));
+        assert_eq!(err.source().unwrap().to_string(), "broken");
+
+        let err = CdiError::from_errors(vec![
+            err,
+            CdiError::UnresolvableDevice(vec!["vendor.com/device=gpu0".to_string()]),
+        ]);
+        assert_eq!(
+            err.to_string(),
+            "invalid CDI Spec /etc/cdi/vendor.yaml: broken, \
+             unresolvable CDI devices vendor.com/device=gpu0"
+        );
+    }
+}
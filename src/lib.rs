@@ -1,19 +1,32 @@
 pub mod annotations;
+#[cfg(feature = "async")]
+pub mod async_cache;
+pub mod builder;
 pub mod cache;
+pub mod config;
 pub mod container_edits;
 pub mod container_edits_unix;
 pub mod default_cache;
 pub mod device;
+pub mod error;
 pub mod generate;
 pub mod internal;
+pub mod limits;
 pub mod parser;
+pub mod registry;
+pub mod requests;
 #[cfg(feature = "schema-validation")]
 pub mod schema;
 pub mod spec;
 pub mod spec_dirs;
+pub mod spec_source;
 pub mod specs;
+#[cfg(test)]
+mod test_utils;
+pub mod transient;
 pub mod utils;
 pub mod version;
+mod watch;
 
 #[cfg(test)]
 mod tests {}
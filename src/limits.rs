@@ -0,0 +1,210 @@
+use std::{fmt, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::{Cache, CdiOption},
+    error::CdiError,
+};
+
+// Limits bounds the work of loading Spec files, so a huge or malicious
+// Spec file or Spec directory can't stall a Cache refresh. Exceeding a
+// limit is reported as a CdiError::LimitExceeded for the offending path.
+// The defaults are far above what real Specs need.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct Limits {
+    // max_file_size is the maximum size of a Spec file in bytes.
+    pub max_file_size: u64,
+    // max_spec_files is the maximum number of Spec files loaded by a
+    // refresh, over all Spec directories.
+    pub max_spec_files: u64,
+    // max_depth is the maximum depth of subdirectories scanned below a
+    // Spec directory.
+    pub max_depth: u64,
+    // max_devices is the maximum number of devices of a Spec.
+    pub max_devices: u64,
+    // max_yaml_aliases is the maximum number of aliases in a Spec file.
+    // Aliases are expanded when a Spec is parsed, so a few nested ones
+    // can expand into a huge document.
+    pub max_yaml_aliases: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_file_size: 4 << 20,
+            max_spec_files: 4096,
+            max_depth: 16,
+            max_devices: 4096,
+            max_yaml_aliases: 128,
+        }
+    }
+}
+
+// Limit is one of the Limits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    FileSize,
+    SpecFiles,
+    Depth,
+    Devices,
+    YamlAliases,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unit = match self {
+            Limit::FileSize => "bytes",
+            Limit::SpecFiles => "Spec files",
+            Limit::Depth => "nested directories",
+            Limit::Devices => "devices",
+            Limit::YamlAliases => "YAML aliases",
+        };
+        f.write_str(unit)
+    }
+}
+
+impl Limits {
+    // check returns an error for path if value exceeds the given limit.
+    pub(crate) fn check(&self, limit: Limit, path: &Path, value: u64) -> Result<(), CdiError> {
+        let max = match limit {
+            Limit::FileSize => self.max_file_size,
+            Limit::SpecFiles => self.max_spec_files,
+            Limit::Depth => self.max_depth,
+            Limit::Devices => self.max_devices,
+            Limit::YamlAliases => self.max_yaml_aliases,
+        };
+        if value > max {
+            return Err(CdiError::LimitExceeded {
+                path: path.display().to_string(),
+                limit,
+                max,
+            });
+        }
+        Ok(())
+    }
+}
+
+// with_limits returns an option to set the limits for loading Spec files.
+pub fn with_limits(limits: Limits) -> CdiOption {
+    Box::new(move |c: &mut Cache| {
+        c.limits = limits;
+    })
+}
+
+// count_yaml_aliases counts the aliases ("*anchor") in YAML data without
+// parsing it. Quoted scalars and comments are skipped. The count errs on
+// the high side for the odd block scalar line starting with a '*'.
+pub(crate) fn count_yaml_aliases(data: &[u8]) -> u64 {
+    let mut count = 0;
+    // node_start is set where a new node, and so an alias, can start.
+    let mut node_start = true;
+    let mut flow_depth = 0;
+    let mut prev = b'\n';
+    let mut i = 0;
+
+    while i < data.len() {
+        let c = data[i];
+        let next_blank = data.get(i + 1).is_none_or(|n| n.is_ascii_whitespace());
+        match c {
+            b'#' if prev.is_ascii_whitespace() => {
+                while i < data.len() && data[i] != b'\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            b'"' | b'\'' if node_start => {
+                i += 1;
+                while i < data.len() {
+                    if data[i] == b'\\' && c == b'"' {
+                        i += 1;
+                    } else if data[i] == c {
+                        // '' is an escaped quote in single quoted scalars.
+                        if c == b'\'' && data.get(i + 1) == Some(&b'\'') {
+                            i += 1;
+                        } else {
+                            break;
+                        }
+                    }
+                    i += 1;
+                }
+                node_start = false;
+            }
+            // Anchors and tags are followed by the node they apply to.
+            b'&' | b'!' if node_start => {
+                while i < data.len() && !b" \t\r\n,[]{}".contains(&data[i]) {
+                    i += 1;
+                }
+                continue;
+            }
+            b'*' if node_start => {
+                count += 1;
+                node_start = false;
+            }
+            b'\n' => node_start = true,
+            b'-' | b'?' | b':' if next_blank => node_start = true,
+            b'[' | b'{' if node_start => {
+                flow_depth += 1;
+            }
+            b']' | b'}' if flow_depth > 0 => {
+                flow_depth -= 1;
+                node_start = false;
+            }
+            b',' if flow_depth > 0 => node_start = true,
+            c if c.is_ascii_whitespace() => {}
+            _ => node_start = false,
+        }
+        prev = data.get(i).copied().unwrap_or(b'\n');
+        i += 1;
+    }
+
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yaml_aliases_are_counted_outside_of_scalars() {
+        let data = br#"
+base: &base ["A=1"]
+nested: &nested [*base]
+devices:
+  - name: "*not-an-alias"
+    env: *base
+    other: [*base, *base] # *comment
+    plain: a *b
+    quoted: 'it''s *x'
+  - *base
+"#;
+        assert_eq!(count_yaml_aliases(data), 5);
+        assert_eq!(count_yaml_aliases(b"cdiVersion: \"0.6.0\"\n"), 0);
+    }
+
+    #[test]
+    fn check_reports_the_limit_and_path() {
+        let limits = Limits {
+            max_devices: 2,
+            ..Default::default()
+        };
+        let path = Path::new("/etc/cdi/vendor.yaml");
+        limits.check(Limit::Devices, path, 2).unwrap();
+
+        let err = limits.check(Limit::Devices, path, 3).unwrap_err();
+        assert!(matches!(
+            err,
+            CdiError::LimitExceeded {
+                limit: Limit::Devices,
+                max: 2,
+                ..
+            }
+        ));
+        assert_eq!(err.get_path(), Some("/etc/cdi/vendor.yaml"));
+        assert_eq!(
+            err.to_string(),
+            "/etc/cdi/vendor.yaml exceeds the limit of 2 devices"
+        );
+    }
+}
@@ -84,6 +84,50 @@ pub(crate) fn parse_device(device: &str) -> (&str, &str, &str) {
     (vendor, class, name)
 }
 
+// IsDevicePattern tests if a device request is a glob pattern, which
+// contains at least one '*' or '?' wildcard.
+pub fn is_device_pattern(request: &str) -> bool {
+    request.contains(['*', '?'])
+}
+
+// MatchDevicePattern tests if a qualified device name matches the given
+// glob pattern. A '*' matches any sequence of characters and a '?' any
+// single character, but neither matches the '/' and '=' separators of
+// the name. So the vendor, class and name parts are matched one by one,
+// for instance "vendor.com/gpu=mig-1g.*" or "vendor.com/*=*".
+pub fn match_device_pattern(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    match_glob(&pattern, &name)
+}
+
+fn match_glob(pattern: &[char], name: &[char]) -> bool {
+    let is_separator = |c: char| c == '/' || c == '=';
+
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => {
+            for skip in 0..=name.len() {
+                if match_glob(rest, &name[skip..]) {
+                    return true;
+                }
+                if skip == name.len() || is_separator(name[skip]) {
+                    break;
+                }
+            }
+            false
+        }
+        Some(('?', rest)) => match name.split_first() {
+            Some((c, name)) => !is_separator(*c) && match_glob(rest, name),
+            None => false,
+        },
+        Some((p, rest)) => match name.split_first() {
+            Some((c, name)) => p == c && match_glob(rest, name),
+            None => false,
+        },
+    }
+}
+
 // ParseQualifier splits a device qualifier into vendor and class.
 // The syntax for a device qualifier is
 //
@@ -231,6 +275,34 @@ mod tests {
         assert_eq!(name, "0");
     }
 
+    #[test]
+    fn match_device_pattern() {
+        assert!(parser::is_device_pattern("vendor.com/gpu=*"));
+        assert!(!parser::is_device_pattern("vendor.com/gpu=0"));
+
+        let name = "vendor.com/gpu=mig-1g.5gb";
+        for pattern in [
+            "vendor.com/gpu=*",
+            "vendor.com/gpu=mig-1g.*",
+            "vendor.com/*=*",
+            "*/*=*",
+            "vendor.com/gp?=mig-?g.5gb",
+            name,
+        ] {
+            assert!(parser::match_device_pattern(pattern, name), "{pattern}");
+        }
+        for pattern in [
+            "vendor.com/gpu=mig-2g.*",
+            "vendor.com/nic=*",
+            "vendor.com*",
+            "*=mig-1g.5gb",
+            "vendor.com/gpu?mig-1g.5gb",
+            "vendor.com/gpu=",
+        ] {
+            assert!(!parser::match_device_pattern(pattern, name), "{pattern}");
+        }
+    }
+
     #[test]
     fn parse_qualifier() {
         let qualifier = "nvidia.com/gpu";
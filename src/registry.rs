@@ -0,0 +1,439 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use anyhow::Result;
+use oci_spec::runtime as oci;
+
+use crate::{
+    cache::{spec_file_name, Cache, Snapshot},
+    device::Device,
+    error::CdiError,
+    spec::{new_spec, Spec},
+    specs::config::Spec as CDISpec,
+};
+
+// The registry traits split up what a Cache offers, like the Registry
+// interfaces of the Go implementation, so code using CDI can depend on
+// just the part it needs and tests can replace it, for instance with a
+// MemoryRegistry.
+
+// RegistryResolver resolves and injects CDI devices into OCI Specs.
+pub trait RegistryResolver {
+    // inject_devices injects the given devices into an OCI Spec. Device
+    // requests may be glob patterns. Devices which can't be resolved make
+    // it fail with CdiError::UnresolvableDevice. The qualified names of the
+    // injected devices are returned.
+    fn inject_devices(
+        &self,
+        oci_spec: &mut oci::Spec,
+        devices: Vec<String>,
+    ) -> Result<Vec<String>, CdiError>;
+}
+
+// RegistryDeviceDB queries the devices of a registry.
+pub trait RegistryDeviceDB {
+    // get_device returns the device with the given qualified name.
+    fn get_device(&self, name: &str) -> Option<Device>;
+    // list_devices lists the qualified names of all devices, sorted.
+    fn list_devices(&self) -> Vec<String>;
+}
+
+// RegistrySpecDB queries and updates the Specs of a registry.
+pub trait RegistrySpecDB {
+    // list_vendors lists the vendors of all Specs, sorted.
+    fn list_vendors(&self) -> Vec<String>;
+    // list_classes lists the device classes of all Specs, sorted.
+    fn list_classes(&self) -> Vec<String>;
+    // get_vendor_specs returns the Specs of the given vendor.
+    fn get_vendor_specs(&self, vendor: &str) -> Vec<Spec>;
+    // get_spec_errors returns the errors encountered for the given Spec.
+    fn get_spec_errors(&self, spec: &Spec) -> Vec<CdiError>;
+    // write_spec adds a Spec with the given name to the registry.
+    fn write_spec(&self, raw: &CDISpec, name: &str) -> Result<(), CdiError>;
+    // remove_spec removes the Spec with the given name from the registry.
+    fn remove_spec(&self, name: &str) -> Result<(), CdiError>;
+}
+
+// RegistryRefresher refreshes a registry and reports its errors.
+pub trait RegistryRefresher {
+    // refresh reloads the Specs of the registry.
+    fn refresh(&self) -> Result<(), CdiError>;
+    // get_errors returns the errors of the last refresh, keyed by path.
+    fn get_errors(&self) -> HashMap<String, Vec<CdiError>>;
+    // get_spec_directories returns the Spec directories of the registry.
+    fn get_spec_directories(&self) -> Vec<String>;
+    // get_spec_dir_errors returns the errors of the Spec directories.
+    fn get_spec_dir_errors(&self) -> HashMap<String, CdiError>;
+}
+
+// Registry is a complete CDI registry.
+pub trait Registry: RegistryResolver + RegistryRefresher {
+    // device_db returns the device queries of the registry.
+    fn device_db(&self) -> &dyn RegistryDeviceDB;
+    // spec_db returns the Spec queries of the registry.
+    fn spec_db(&self) -> &dyn RegistrySpecDB;
+}
+
+impl RegistryResolver for Cache {
+    fn inject_devices(
+        &self,
+        oci_spec: &mut oci::Spec,
+        devices: Vec<String>,
+    ) -> Result<Vec<String>, CdiError> {
+        Cache::inject_devices(self, Some(oci_spec), devices)
+    }
+}
+
+impl RegistryDeviceDB for Cache {
+    fn get_device(&self, name: &str) -> Option<Device> {
+        Cache::get_device(self, name)
+    }
+
+    fn list_devices(&self) -> Vec<String> {
+        Cache::list_devices(self)
+    }
+}
+
+impl RegistrySpecDB for Cache {
+    fn list_vendors(&self) -> Vec<String> {
+        Cache::list_vendors(self)
+    }
+
+    fn list_classes(&self) -> Vec<String> {
+        Cache::list_classes(self)
+    }
+
+    fn get_vendor_specs(&self, vendor: &str) -> Vec<Spec> {
+        Cache::get_vendor_specs(self, vendor)
+    }
+
+    fn get_spec_errors(&self, spec: &Spec) -> Vec<CdiError> {
+        Cache::get_spec_errors(self, &spec.get_path())
+    }
+
+    fn write_spec(&self, raw: &CDISpec, name: &str) -> Result<(), CdiError> {
+        Cache::write_spec(self, raw, name)
+    }
+
+    fn remove_spec(&self, name: &str) -> Result<(), CdiError> {
+        Cache::remove_spec(self, name)
+    }
+}
+
+impl RegistryRefresher for Cache {
+    fn refresh(&self) -> Result<(), CdiError> {
+        Cache::refresh(self)
+    }
+
+    fn get_errors(&self) -> HashMap<String, Vec<CdiError>> {
+        Cache::get_errors(self)
+    }
+
+    fn get_spec_directories(&self) -> Vec<String> {
+        self.get_spec_dirs()
+            .into_iter()
+            .map(|dir| dir.path)
+            .collect()
+    }
+
+    fn get_spec_dir_errors(&self) -> HashMap<String, CdiError> {
+        Cache::get_dir_errors(self)
+    }
+}
+
+impl Registry for Cache {
+    fn device_db(&self) -> &dyn RegistryDeviceDB {
+        self
+    }
+
+    fn spec_db(&self) -> &dyn RegistrySpecDB {
+        self
+    }
+}
+
+// MemoryRegistry is a Registry which keeps its Specs in memory instead of
+// loading them from Spec directories, for tests of code using a Registry.
+// Specs are added with write_spec() and validated like Spec files, their
+// names are checked like Spec file names. Devices are resolved and injected
+// like by a Cache, except that a device defined by several Specs is always
+// an error.
+#[derive(Default)]
+pub struct MemoryRegistry {
+    // specs are the Specs by file name.
+    specs: RwLock<BTreeMap<String, Spec>>,
+    // snapshot is built from the Specs when it is first needed after a
+    // change, so a series of writes doesn't rebuild it every time. It is
+    // only set and cleared with specs locked.
+    snapshot: Mutex<Option<Arc<Snapshot>>>,
+}
+
+impl MemoryRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // snapshot returns a Snapshot of the Specs for resolving devices.
+    fn snapshot(&self) -> Arc<Snapshot> {
+        let specs = self.specs.read().unwrap();
+        let mut snapshot = self.snapshot.lock().unwrap();
+        snapshot
+            .get_or_insert_with(|| {
+                let mut snapshot = Snapshot::default();
+                for spec in specs.values() {
+                    for dev in spec.devices.values() {
+                        snapshot
+                            .devices
+                            .insert(dev.get_qualified_name(), dev.clone());
+                    }
+                    snapshot
+                        .specs
+                        .entry(spec.get_vendor())
+                        .or_default()
+                        .push(spec.clone());
+                }
+                Arc::new(snapshot)
+            })
+            .clone()
+    }
+}
+
+impl RegistryResolver for MemoryRegistry {
+    fn inject_devices(
+        &self,
+        oci_spec: &mut oci::Spec,
+        devices: Vec<String>,
+    ) -> Result<Vec<String>, CdiError> {
+        let (mut edits, result) = self.snapshot().resolve_requests(devices)?;
+        if !result.unresolved.is_empty() {
+            let unresolved = result.unresolved.into_iter().map(|u| u.name).collect();
+            return Err(CdiError::UnresolvableDevice(unresolved));
+        }
+
+        edits.apply(oci_spec)?;
+        Ok(result.injected)
+    }
+}
+
+impl RegistryDeviceDB for MemoryRegistry {
+    fn get_device(&self, name: &str) -> Option<Device> {
+        self.snapshot().devices.get(name).cloned()
+    }
+
+    fn list_devices(&self) -> Vec<String> {
+        let mut devices: Vec<String> = self.snapshot().devices.keys().cloned().collect();
+        devices.sort();
+        devices
+    }
+}
+
+impl RegistrySpecDB for MemoryRegistry {
+    fn list_vendors(&self) -> Vec<String> {
+        let specs = self.specs.read().unwrap();
+        let vendors: BTreeSet<String> = specs.values().map(|spec| spec.get_vendor()).collect();
+        vendors.into_iter().collect()
+    }
+
+    fn list_classes(&self) -> Vec<String> {
+        let specs = self.specs.read().unwrap();
+        let classes: BTreeSet<String> = specs.values().map(|spec| spec.get_class()).collect();
+        classes.into_iter().collect()
+    }
+
+    fn get_vendor_specs(&self, vendor: &str) -> Vec<Spec> {
+        let specs = self.specs.read().unwrap();
+        specs
+            .values()
+            .filter(|spec| spec.get_vendor() == vendor)
+            .cloned()
+            .collect()
+    }
+
+    fn get_spec_errors(&self, _spec: &Spec) -> Vec<CdiError> {
+        Vec::new()
+    }
+
+    // write_spec validates the Spec and adds it, replacing a Spec with
+    // the same name. Specs defining devices of other Specs are refused.
+    fn write_spec(&self, raw: &CDISpec, name: &str) -> Result<(), CdiError> {
+        let name = &spec_file_name(name)?;
+        let spec = new_spec(raw, &PathBuf::from(name), 0)?;
+
+        let mut specs = self.specs.write().unwrap();
+        for (other_name, other) in specs.iter() {
+            let same_kind =
+                other.get_vendor() == spec.get_vendor() && other.get_class() == spec.get_class();
+            if other_name == name || !same_kind {
+                continue;
+            }
+            if let Some(dev) = spec
+                .devices
+                .values()
+                .find(|dev| other.devices.contains_key(&dev.cdi_device.name))
+            {
+                return Err(CdiError::Conflict {
+                    name: dev.get_qualified_name(),
+                    dev_path: spec.get_path(),
+                    old_path: other.get_path(),
+                });
+            }
+        }
+        specs.insert(name.to_owned(), spec);
+        *self.snapshot.lock().unwrap() = None;
+        Ok(())
+    }
+
+    fn remove_spec(&self, name: &str) -> Result<(), CdiError> {
+        let name = spec_file_name(name)?;
+        let mut specs = self.specs.write().unwrap();
+        if specs.remove(&name).is_some() {
+            *self.snapshot.lock().unwrap() = None;
+        }
+        Ok(())
+    }
+}
+
+impl RegistryRefresher for MemoryRegistry {
+    // refresh is a no-op, the Specs are always current.
+    fn refresh(&self) -> Result<(), CdiError> {
+        Ok(())
+    }
+
+    fn get_errors(&self) -> HashMap<String, Vec<CdiError>> {
+        HashMap::new()
+    }
+
+    fn get_spec_directories(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    fn get_spec_dir_errors(&self) -> HashMap<String, CdiError> {
+        HashMap::new()
+    }
+}
+
+impl Registry for MemoryRegistry {
+    fn device_db(&self) -> &dyn RegistryDeviceDB {
+        self
+    }
+
+    fn spec_db(&self) -> &dyn RegistrySpecDB {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{spec_dirs::with_spec_dirs, test_utils::raw_spec};
+    use std::fs;
+
+    // exercise uses a registry only through the Registry trait.
+    fn exercise(registry: &dyn Registry) {
+        registry
+            .spec_db()
+            .write_spec(&raw_spec("vendor.com/gpu", "GPU=0"), "vendor-gpu")
+            .unwrap();
+        registry
+            .spec_db()
+            .write_spec(&raw_spec("vendor.com/nic", "NIC=0"), "vendor-nic")
+            .unwrap();
+
+        assert_eq!(
+            registry.device_db().list_devices(),
+            vec!["vendor.com/gpu=gpu0", "vendor.com/nic=gpu0"]
+        );
+        assert_eq!(registry.spec_db().list_vendors(), vec!["vendor.com"]);
+        assert_eq!(registry.spec_db().list_classes(), vec!["gpu", "nic"]);
+        assert_eq!(registry.spec_db().get_vendor_specs("vendor.com").len(), 2);
+        let dev = registry
+            .device_db()
+            .get_device("vendor.com/gpu=gpu0")
+            .unwrap();
+        assert!(registry
+            .spec_db()
+            .get_spec_errors(&dev.get_spec())
+            .is_empty());
+
+        let mut oci_spec = oci::Spec::default();
+        let injected = registry
+            .inject_devices(&mut oci_spec, vec!["vendor.com/*=*".to_string()])
+            .unwrap();
+        assert_eq!(injected, vec!["vendor.com/gpu=gpu0", "vendor.com/nic=gpu0"]);
+        let env = oci_spec.process().as_ref().unwrap().env().clone().unwrap();
+        assert!(env.contains(&"GPU=0".to_string()));
+        assert!(env.contains(&"NIC=0".to_string()));
+
+        let err = registry
+            .inject_devices(&mut oci_spec, vec!["vendor.com/gpu=gpu1".to_string()])
+            .unwrap_err();
+        assert!(matches!(err, CdiError::UnresolvableDevice(_)));
+
+        registry.spec_db().remove_spec("vendor-nic").unwrap();
+        registry.refresh().unwrap();
+        assert_eq!(
+            registry.device_db().list_devices(),
+            vec!["vendor.com/gpu=gpu0"]
+        );
+        assert!(registry.get_errors().is_empty());
+    }
+
+    #[test]
+    fn memory_registry_behaves_like_a_cache() {
+        let memory = MemoryRegistry::new();
+        exercise(&memory);
+        assert!(memory.get_spec_directories().is_empty());
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = Cache::default();
+        with_spec_dirs(&[dir.path().to_str().unwrap()])(&mut cache);
+        exercise(&cache);
+        assert_eq!(
+            cache.get_spec_directories(),
+            vec![dir.path().to_str().unwrap()]
+        );
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn memory_registry_refuses_conflicting_specs() {
+        let registry = MemoryRegistry::new();
+        registry
+            .write_spec(&raw_spec("vendor.com/gpu", "GPU=0"), "a")
+            .unwrap();
+        let err = registry
+            .write_spec(&raw_spec("vendor.com/gpu", "GPU=1"), "b")
+            .unwrap_err();
+        assert!(matches!(err, CdiError::Conflict { .. }), "{err:?}");
+
+        // Replacing a Spec by name is fine.
+        registry
+            .write_spec(&raw_spec("vendor.com/gpu", "GPU=1"), "a")
+            .unwrap();
+        let dev = registry.get_device("vendor.com/gpu=gpu0").unwrap();
+        assert_eq!(
+            dev.cdi_device.container_edits.env,
+            Some(vec!["GPU=1".to_string()])
+        );
+
+        // Names are checked and completed like Spec file names.
+        for name in ["", ".", "..", "/", "../a"] {
+            let err = registry
+                .write_spec(&raw_spec("vendor.com/gpu", "GPU=1"), name)
+                .unwrap_err();
+            assert!(
+                err.to_string().contains("invalid Spec file name"),
+                "{name:?}"
+            );
+        }
+        let dev = registry.get_device("vendor.com/gpu=gpu0").unwrap();
+        assert_eq!(dev.get_spec().get_path(), "a.yaml");
+        // The Snapshot is only rebuilt after changes.
+        let snapshot = registry.snapshot();
+        assert!(Arc::ptr_eq(&snapshot, &registry.snapshot()));
+        registry.remove_spec("a.yaml").unwrap();
+        assert!(registry.list_devices().is_empty());
+    }
+}
@@ -0,0 +1,212 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use oci_spec::runtime as oci;
+
+use crate::{annotations::parse_annotations, cache::Cache, error::CdiError, parser};
+
+// RequestResolver collects the CDI device requests which higher layers
+// have encoded in an OCI Spec and injects the requested devices. Requests
+// are taken from
+//   - "cdi.k8s.io/*" annotations, see parse_annotations(),
+//   - the comma separated device names of the env_var environment
+//     variable of the container process, if set,
+//   - mounts with a destination in mount_dir, if set. The qualified
+//     device name is the destination relative to mount_dir, for instance
+//     "<mount_dir>/vendor.com/class=device". The mounts themselves are
+//     left in the OCI Spec.
+#[derive(Clone, Debug)]
+pub struct RequestResolver {
+    pub annotations: bool,
+    pub env_var: Option<String>,
+    pub mount_dir: Option<PathBuf>,
+}
+
+impl Default for RequestResolver {
+    fn default() -> Self {
+        Self {
+            annotations: true,
+            env_var: None,
+            mount_dir: None,
+        }
+    }
+}
+
+impl RequestResolver {
+    // requests returns the device requests of the OCI Spec, in the order
+    // of the sources above. Duplicate requests are dropped. Device names
+    // which are not fully qualified make it fail.
+    pub fn requests(&self, oci_spec: &oci::Spec) -> Result<Vec<String>> {
+        let mut requests = Vec::new();
+
+        if self.annotations {
+            if let Some(annotations) = oci_spec.annotations() {
+                let (_, devices) =
+                    parse_annotations(annotations).context("invalid CDI annotations")?;
+                requests.extend(devices);
+            }
+        }
+
+        if let Some(env_var) = self.env_var.as_deref() {
+            let prefix = format!("{}=", env_var);
+            let env = oci_spec.process().as_ref().and_then(|p| p.env().as_ref());
+            for value in env
+                .into_iter()
+                .flatten()
+                .filter_map(|e| e.strip_prefix(&prefix))
+            {
+                for device in value.split(',').filter(|d| !d.is_empty()) {
+                    requests.push(qualified(device).context(format!("invalid {}", env_var))?);
+                }
+            }
+        }
+
+        if let Some(mount_dir) = self.mount_dir.as_deref() {
+            for mount in oci_spec.mounts().iter().flatten() {
+                if let Some(device) = mount_request(mount.destination(), mount_dir) {
+                    requests.push(qualified(&device).context("invalid CDI request mount")?);
+                }
+            }
+        }
+
+        let mut seen = HashSet::new();
+        requests.retain(|request| seen.insert(request.clone()));
+
+        Ok(requests)
+    }
+
+    // inject injects the devices requested in the OCI Spec, using the given
+    // Cache. The requests are returned. The OCI Spec is left alone if it
+    // holds no requests.
+    pub fn inject(&self, cache: &Cache, oci_spec: &mut oci::Spec) -> Result<Vec<String>, CdiError> {
+        let requests = self.requests(oci_spec)?;
+        if requests.is_empty() {
+            return Ok(requests);
+        }
+
+        cache.inject_devices(Some(oci_spec), requests.clone())?;
+        Ok(requests)
+    }
+}
+
+// mount_request returns the device name encoded in a mount destination
+// within mount_dir.
+fn mount_request(destination: &Path, mount_dir: &Path) -> Option<String> {
+    let name = destination.strip_prefix(mount_dir).ok()?.to_str()?;
+    (!name.is_empty()).then(|| name.to_owned())
+}
+
+fn qualified(device: &str) -> Result<String> {
+    if !parser::is_qualified_name(device) {
+        return Err(anyhow!("invalid CDI device name {}", device));
+    }
+    Ok(device.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{dir_cache, spec_yaml};
+    use oci::{MountBuilder, ProcessBuilder, SpecBuilder};
+    use std::{collections::HashMap, fs};
+
+    fn oci_spec(annotations: &[(&str, &str)], env: &[&str], mounts: &[&str]) -> oci::Spec {
+        let annotations: HashMap<String, String> = annotations
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let mounts = mounts
+            .iter()
+            .map(|m| {
+                MountBuilder::default()
+                    .destination(m)
+                    .source("/dev/null")
+                    .build()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        SpecBuilder::default()
+            .annotations(annotations)
+            .process(
+                ProcessBuilder::default()
+                    .env(env.iter().map(|e| e.to_string()).collect::<Vec<_>>())
+                    .build()
+                    .unwrap(),
+            )
+            .mounts(mounts)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn requests_are_collected_from_all_sources() {
+        let spec = oci_spec(
+            &[
+                ("cdi.k8s.io/b", "vendor.com/gpu=1"),
+                ("cdi.k8s.io/a", "vendor.com/gpu=0,vendor.com/gpu=1"),
+                ("other.io/x", "not-a-device"),
+            ],
+            &["PATH=/bin", "CDI_DEVICES=vendor.com/nic=0,vendor.com/gpu=0"],
+            &[
+                "/run/cdi-requests/vendor.com/fpga=0",
+                "/run/other/vendor.com/fpga=1",
+            ],
+        );
+
+        // Only annotations by default.
+        assert_eq!(
+            RequestResolver::default().requests(&spec).unwrap(),
+            vec!["vendor.com/gpu=0", "vendor.com/gpu=1"]
+        );
+
+        let resolver = RequestResolver {
+            env_var: Some("CDI_DEVICES".to_string()),
+            mount_dir: Some(PathBuf::from("/run/cdi-requests")),
+            ..Default::default()
+        };
+        assert_eq!(
+            resolver.requests(&spec).unwrap(),
+            vec![
+                "vendor.com/gpu=0",
+                "vendor.com/gpu=1",
+                "vendor.com/nic=0",
+                "vendor.com/fpga=0",
+            ]
+        );
+
+        let spec = oci_spec(&[], &["CDI_DEVICES=gpu0"], &[]);
+        let err = resolver.requests(&spec).unwrap_err();
+        assert!(format!("{err:#}").contains("invalid CDI device name gpu0"));
+    }
+
+    #[test]
+    fn inject_applies_the_requested_devices() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("vendor.yaml"),
+            spec_yaml("vendor.com/gpu", "GPU=0"),
+        )
+        .unwrap();
+        let cache = dir_cache(&[dir.path().to_str().unwrap()]);
+        cache.refresh().unwrap();
+
+        let resolver = RequestResolver::default();
+        let mut spec = oci_spec(&[("cdi.k8s.io/a", "vendor.com/gpu=gpu0")], &[], &[]);
+        let requests = resolver.inject(&cache, &mut spec).unwrap();
+        assert_eq!(requests, vec!["vendor.com/gpu=gpu0"]);
+        let env = spec.process().as_ref().unwrap().env().clone().unwrap();
+        assert!(env.contains(&"GPU=0".to_string()));
+
+        let mut spec = oci_spec(&[("cdi.k8s.io/a", "vendor.com/gpu=gpu1")], &[], &[]);
+        let err = resolver.inject(&cache, &mut spec).unwrap_err();
+        assert!(matches!(err, CdiError::UnresolvableDevice(_)));
+
+        let mut spec = oci_spec(&[], &[], &[]);
+        let unchanged = spec.clone();
+        assert!(resolver.inject(&cache, &mut spec).unwrap().is_empty());
+        assert_eq!(spec, unchanged);
+    }
+}
@@ -1,4 +1,11 @@
-use std::{collections::BTreeMap, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::SystemTime,
+};
 
 use anyhow::{anyhow, Context, Result};
 use oci_spec::runtime as oci;
@@ -9,12 +16,14 @@ use crate::{
     container_edits::Validate,
     device::new_device,
     device::Device,
+    error::CdiError,
     internal::validation::validate::validate_spec_annotations,
+    limits::{count_yaml_aliases, Limit, Limits},
     parser::parse_qualifier,
     parser::validate_class_name,
     parser::validate_vendor_name,
     specs::config::Spec as CDISpec,
-    utils::is_cdi_spec,
+    utils::{is_cdi_spec, rename_in},
     version::{
         minimum_required_version, validate_declared_version_fields, VersionWrapper,
         VALID_SPEC_VERSIONS,
@@ -25,6 +34,10 @@ use crate::{
 // dotted value produces "spec..yaml".
 const DEFAULT_SPEC_EXT_SUFFIX: &str = "yaml";
 
+// Spec files are written to a temporary file first; the counter keeps
+// concurrent writers of the same process apart.
+static TMP_SPEC_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
 // Spec represents a single CDI Spec. It is usually loaded from a
 // file and stored in a cache. The Spec has an associated priority.
 // This priority is inherited from the associated priority of the
@@ -39,6 +52,9 @@ pub struct Spec {
     path: String,
     priority: i32,
     pub devices: BTreeMap<String, Device>,
+    // modified is the modification time of the Spec file, for Specs which
+    // are loaded from one.
+    modified: Option<SystemTime>,
 }
 
 impl Spec {
@@ -47,6 +63,21 @@ impl Spec {
         self.vendor.clone()
     }
 
+    // get_modified returns the modification time of the Spec file, if the
+    // Spec is loaded from one.
+    pub(crate) fn get_modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    // set_modified records the modification time of the Spec file, for
+    // the Spec and its devices.
+    pub(crate) fn set_modified(&mut self, modified: Option<SystemTime>) {
+        self.modified = modified;
+        for dev in self.devices.values_mut() {
+            dev.spec_mut().modified = modified;
+        }
+    }
+
     // get_class returns the device class of this Spec.
     pub fn get_class(&self) -> String {
         self.class.clone()
@@ -84,7 +115,7 @@ impl Spec {
 
     // validate the Spec.
     pub fn validate(&mut self) -> Result<BTreeMap<String, Device>> {
-        validate_version(&self.cdi_spec).context("validate cdi version failed")?;
+        validate_version(&self.cdi_spec, &self.path).context("validate cdi version failed")?;
         validate_vendor_name(&self.vendor).context("validate vendor name failed")?;
         validate_class_name(&self.class).context("validate class name failed")?;
         validate_spec_annotations(&self.cdi_spec.kind, &self.cdi_spec.annotations)
@@ -120,17 +151,83 @@ impl Spec {
 
         Ok(())
     }
-}
 
-pub fn parse_spec(path: &PathBuf) -> Result<CDISpec> {
-    if !path.exists() {
-        return Err(anyhow!("CDI spec path not found"));
+    // write the CDI Spec to the file associated with it during instantiation
+    // by new_spec() or read_spec(). The Spec is written to a temporary file
+    // in the same directory first, then renamed into place, so readers never
+    // see a partially written Spec file.
+    pub fn write(&self, overwrite: bool) -> Result<()> {
+        validate_spec(&self.cdi_spec)?;
+
+        let path = Path::new(&self.path);
+        let data = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::to_vec(&self.cdi_spec).context("failed to marshal Spec file")?
+        } else {
+            let data =
+                serde_yaml::to_string(&self.cdi_spec).context("failed to marshal Spec file")?;
+            format!("---\n{}", data).into_bytes()
+        };
+
+        let (dir, name) = match (path.parent(), path.file_name()) {
+            (Some(dir), Some(name)) => (dir, name),
+            _ => return Err(anyhow!("invalid Spec file path {:?}", self.path)),
+        };
+        fs::create_dir_all(dir).context("failed to create Spec dir")?;
+
+        let tmp_name = format!(
+            "spec.{}.{}.tmp",
+            std::process::id(),
+            TMP_SPEC_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let tmp_path = dir.join(&tmp_name);
+        let mut tmp = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)
+            .context("failed to create Spec file")?;
+        let written = tmp.write_all(&data).and_then(|_| tmp.sync_all());
+        drop(tmp);
+
+        let result = written
+            .map_err(anyhow::Error::from)
+            .and_then(|_| rename_in(dir, Path::new(&tmp_name), Path::new(name), overwrite));
+        if let Err(e) = result {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e.context("failed to write Spec file"));
+        }
+
+        Ok(())
     }
+}
 
-    let data = std::fs::read(path).context("read config file")?;
-    let cdi_spec: CDISpec = serde_yaml::from_slice(&data).context("serde yaml read from file")?;
+pub fn parse_spec(path: &PathBuf) -> Result<CDISpec, CdiError> {
+    parse_spec_with_limits(path, &Limits::default())
+}
 
-    Ok(cdi_spec)
+// parse_spec_with_limits parses the given CDI Spec file like parse_spec,
+// refusing files which are too large or use too many YAML aliases.
+pub(crate) fn parse_spec_with_limits(path: &PathBuf, limits: &Limits) -> Result<CDISpec, CdiError> {
+    let parse = || -> Result<CDISpec> {
+        if !path.exists() {
+            return Err(anyhow!("CDI spec path not found"));
+        }
+
+        // Read at most one byte more than allowed, the size reported by
+        // the file system can't be trusted for special files.
+        let mut data = Vec::new();
+        File::open(path)
+            .and_then(|file| file.take(limits.max_file_size + 1).read_to_end(&mut data))
+            .context("read config file")?;
+        limits.check(Limit::FileSize, path, data.len() as u64)?;
+        limits.check(Limit::YamlAliases, path, count_yaml_aliases(&data))?;
+
+        let cdi_spec: CDISpec =
+            serde_yaml::from_slice(&data).context("serde yaml read from file")?;
+
+        Ok(cdi_spec)
+    };
+
+    parse().map_err(|err| CdiError::invalid_spec(path, err.context("parse spec file failed")))
 }
 
 // validate_spec validates the Spec against the JSON schema; a no-op
@@ -150,44 +247,166 @@ pub fn validate_spec(raw_spec: &CDISpec) -> Result<()> {
 // read_spec reads the given CDI Spec file. The resulting Spec is
 // assigned the given priority. If reading or parsing the Spec
 // data fails read_spec returns a nil Spec and an error.
-pub fn read_spec(path: &PathBuf, priority: i32) -> Result<Spec> {
-    let raw_spec = parse_spec(path).context("parse spec file failed")?;
-    let cdi_spec = new_spec(&raw_spec, path, priority).context("create a new cdi spec failed")?;
+pub fn read_spec(path: &PathBuf, priority: i32) -> Result<Spec, CdiError> {
+    read_spec_with_limits(path, priority, &Limits::default())
+}
 
-    Ok(cdi_spec)
+// read_spec_with_limits reads the given CDI Spec file like read_spec,
+// enforcing the given limits.
+pub(crate) fn read_spec_with_limits(
+    path: &PathBuf,
+    priority: i32,
+    limits: &Limits,
+) -> Result<Spec, CdiError> {
+    let raw_spec = parse_spec_with_limits(path, limits)?;
+    limits.check(Limit::Devices, path, raw_spec.devices.len() as u64)?;
+    new_spec(&raw_spec, path, priority)
 }
 
 // new_spec creates a new Spec from the given CDI Spec data. The
 // Spec is marked as loaded from the given path with the given
 // priority. If Spec data validation fails new_spec returns an error.
-pub fn new_spec(raw_spec: &CDISpec, path: &PathBuf, priority: i32) -> Result<Spec> {
+pub fn new_spec(raw_spec: &CDISpec, path: &PathBuf, priority: i32) -> Result<Spec, CdiError> {
+    check_spec(raw_spec, path)?;
+    restore_spec(raw_spec, path, priority)
+}
+
+// new_source_spec creates a new Spec from CDI Spec data provided by a
+// SpecSource. It is validated like by new_spec, but the origin of the data
+// is kept as it is for the path of the Spec, it needn't be a file path.
+pub(crate) fn new_source_spec(
+    raw_spec: &CDISpec,
+    origin: &str,
+    priority: i32,
+) -> Result<Spec, CdiError> {
+    check_spec(raw_spec, Path::new(origin))?;
+    build_spec(raw_spec, Path::new(origin), origin.to_owned(), priority)
+}
+
+// check_spec does the validation of new_spec before the Spec is built.
+fn check_spec(raw_spec: &CDISpec, path: &Path) -> Result<(), CdiError> {
     if raw_spec.devices.is_empty() {
-        return Err(anyhow::anyhow!("invalid spec, no devices"));
+        return Err(CdiError::invalid_spec(
+            path,
+            anyhow!("invalid spec, no devices"),
+        ));
     }
 
-    validate_spec(raw_spec).context("invalid CDI Spec")?;
+    if let Err(err) = validate_spec(raw_spec) {
+        return Err(CdiError::SchemaViolation {
+            path: path.display().to_string(),
+            reason: err.root_cause().to_string(),
+        });
+    }
 
+    Ok(())
+}
+
+// restore_spec creates a new Spec from CDI Spec data which has already
+// passed the JSON schema validation of new_spec, for instance when it was
+// saved by an earlier scan. Only the cheaper semantic validation is done.
+pub(crate) fn restore_spec(
+    raw_spec: &CDISpec,
+    path: &PathBuf,
+    priority: i32,
+) -> Result<Spec, CdiError> {
     let mut cleaned_path = clean(path);
     if !is_cdi_spec(&cleaned_path) {
         cleaned_path.set_extension(DEFAULT_SPEC_EXT_SUFFIX);
     }
 
+    build_spec(raw_spec, path, cleaned_path.display().to_string(), priority)
+}
+
+// build_spec creates a Spec with the given path and validates its devices.
+// Errors are reported for origin, the path the Spec data was given for.
+fn build_spec(
+    raw_spec: &CDISpec,
+    origin: &Path,
+    path: String,
+    priority: i32,
+) -> Result<Spec, CdiError> {
     let (vendor, class) = parse_qualifier(&raw_spec.kind);
 
     let mut spec: Spec = Spec {
         cdi_spec: raw_spec.clone(),
-        path: cleaned_path.display().to_string(),
+        path,
         priority,
         vendor: vendor.to_owned(),
         class: class.to_owned(),
         ..Default::default()
     };
-    spec.devices = spec.validate().context("validate spec failed")?;
+    spec.devices = spec
+        .validate()
+        .map_err(|err| CdiError::invalid_spec(origin, err.context("validate spec failed")))?;
 
     Ok(spec)
 }
 
-fn validate_version(cdi_spec: &CDISpec) -> Result<()> {
+// generate_spec_name generates a vendor+class scoped Spec file name. The
+// name can be passed to Cache::write_spec() to write a Spec file to the
+// file system.
+//
+// vendor and class should match the vendor and class of the CDI Spec.
+// The file name is generated without a ".json" or ".yaml" extension.
+// The caller can append the desired extension to choose a particular
+// encoding. Otherwise write_spec() will use its default encoding.
+//
+// This function always returns the same name for the same vendor/class
+// combination. Therefore it cannot be used as such to generate multiple
+// Spec file names for a single vendor and class.
+pub fn generate_spec_name(vendor: &str, class: &str) -> String {
+    format!("{}-{}", vendor, class)
+}
+
+// generate_transient_spec_name generates a vendor+class scoped transient
+// Spec file name. The name can be passed to Cache::write_spec() to write
+// a Spec file to the file system.
+//
+// Transient Specs are those whose lifecycle is tied to that of some
+// external entity, for instance a container. vendor and class should
+// match the vendor and class of the CDI Spec. transient_id should be
+// unique among all CDI users on the same host that might generate
+// transient Spec files using the same vendor/class combination. If
+// the external entity to which the lifecycle of the transient Spec
+// is tied to has a unique ID of its own, then this is usually a
+// good choice for transient_id.
+//
+// The file name is generated without a ".json" or ".yaml" extension.
+// The caller can append the desired extension to choose a particular
+// encoding. Otherwise write_spec() will use its default encoding.
+pub fn generate_transient_spec_name(vendor: &str, class: &str, transient_id: &str) -> String {
+    let transient_id = transient_id.replace('/', "_");
+    format!("{}_{}", generate_spec_name(vendor, class), transient_id)
+}
+
+// generate_name_for_spec generates a name for the given Spec using
+// generate_spec_name with the vendor and class taken from the Spec.
+// If the Spec does not contain a valid vendor or class, it returns
+// an error.
+pub fn generate_name_for_spec(raw: &CDISpec) -> Result<String> {
+    let (vendor, class) = parse_qualifier(&raw.kind);
+    if vendor.is_empty() {
+        return Err(anyhow!("invalid vendor/class {:?} in Spec", raw.kind));
+    }
+
+    Ok(generate_spec_name(vendor, class))
+}
+
+// generate_name_for_transient_spec generates a name for the given Spec
+// using generate_transient_spec_name with the vendor and class taken
+// from the Spec. If the Spec does not contain a valid vendor or class,
+// it returns an error.
+pub fn generate_name_for_transient_spec(raw: &CDISpec, transient_id: &str) -> Result<String> {
+    let (vendor, class) = parse_qualifier(&raw.kind);
+    if vendor.is_empty() {
+        return Err(anyhow!("invalid vendor/class {:?} in Spec", raw.kind));
+    }
+
+    Ok(generate_transient_spec_name(vendor, class, transient_id))
+}
+
+fn validate_version(cdi_spec: &CDISpec, path: &str) -> Result<()> {
     let version = &cdi_spec.version;
     if !VALID_SPEC_VERSIONS.is_valid_version(version) {
         return Err(anyhow::anyhow!("invalid version {}", version));
@@ -199,10 +418,12 @@ fn validate_version(cdi_spec: &CDISpec) -> Result<()> {
         .with_context(|| "could not determine minimum required version")?;
 
     if min_version.is_greater_than(&VersionWrapper::new(version)) {
-        return Err(anyhow::anyhow!(
-            "the spec version must be at least v{}",
-            min_version
-        ));
+        return Err(CdiError::VersionTooOld {
+            path: path.to_owned(),
+            version: version.to_owned(),
+            required: min_version.to_string(),
+        }
+        .into());
     }
 
     Ok(())
@@ -212,13 +433,22 @@ fn validate_version(cdi_spec: &CDISpec) -> Result<()> {
 mod tests {
     use super::*;
     use oci_spec::runtime as oci;
-    use std::path::PathBuf;
+    use std::{error::Error, path::PathBuf};
+
+    // error_chain formats an error together with its causes.
+    fn error_chain(err: &CdiError) -> String {
+        std::iter::successors(Some(err as &dyn Error), |err| (*err).source())
+            .map(|err| err.to_string())
+            .collect::<Vec<_>>()
+            .join(": ")
+    }
 
     #[test]
     fn parse_spec_rejects_unknown_fields() {
         let path = PathBuf::from("tests/fixtures/cdi-unknown-field.yaml");
         let err = parse_spec(&path).expect_err("unknown field should fail");
-        assert!(err.to_string().contains("serde yaml read from file"));
+        assert!(matches!(err, CdiError::InvalidSpec { .. }));
+        assert!(error_chain(&err).contains("serde yaml read from file"));
     }
 
     #[test]
@@ -226,7 +456,7 @@ mod tests {
         let path = PathBuf::from("tests/fixtures/cdi-empty-devices.yaml");
         let raw = parse_spec(&path).expect("empty device fixture parses");
         let err = new_spec(&raw, &path, 0).expect_err("empty devices should fail");
-        assert!(err.to_string().contains("no devices"));
+        assert!(error_chain(&err).contains("no devices"));
     }
 
     #[test]
@@ -235,7 +465,7 @@ mod tests {
         let raw = parse_spec(&path).expect("legacy fields should parse before version validation");
         let err = new_spec(&raw, &path, 0).expect_err("v1.1.0 should reject legacy fields");
 
-        assert!(format!("{err:#}").contains("enableCMT"));
+        assert!(error_chain(&err).contains("enableCMT"));
     }
 
     #[test]
@@ -338,7 +568,31 @@ mod tests {
     #[test]
     fn parse_spec_requires_an_existing_path() {
         let err = parse_spec(&PathBuf::from("/nonexistent/spec.yaml")).unwrap_err();
-        assert!(err.to_string().contains("not found"));
+        assert_eq!(err.get_path(), Some("/nonexistent/spec.yaml"));
+        assert!(error_chain(&err).contains("not found"));
+    }
+
+    #[test]
+    fn generated_spec_names() {
+        assert_eq!(generate_spec_name("vendor.com", "gpu"), "vendor.com-gpu");
+        assert_eq!(
+            generate_transient_spec_name("vendor.com", "gpu", "ns/claim"),
+            "vendor.com-gpu_ns_claim"
+        );
+
+        let mut raw = crate::specs::config::Spec {
+            kind: "vendor.com/gpu".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(generate_name_for_spec(&raw).unwrap(), "vendor.com-gpu");
+        assert_eq!(
+            generate_name_for_transient_spec(&raw, "1234").unwrap(),
+            "vendor.com-gpu_1234"
+        );
+
+        raw.kind = "gpu".to_string();
+        assert!(generate_name_for_spec(&raw).is_err());
+        assert!(generate_name_for_transient_spec(&raw, "1234").is_err());
     }
 
     #[test]
@@ -350,12 +604,13 @@ mod tests {
             ..Default::default()
         };
         let err = new_spec(&raw, &PathBuf::from("/tmp/x.yaml"), 0).unwrap_err();
-        assert!(format!("{err:?}").contains("invalid version"));
+        assert!(error_chain(&err).contains("invalid version"));
 
         // additionalGIDs require 0.7.0: declaring 0.5.0 understates it
         raw.version = "0.5.0".to_string();
         raw.devices[0].container_edits.additional_gids = Some(vec![5]);
         let err = new_spec(&raw, &PathBuf::from("/tmp/x.yaml"), 0).unwrap_err();
-        assert!(format!("{err:?}").contains("must be at least"));
+        assert!(matches!(err, CdiError::VersionTooOld { .. }), "{err:?}");
+        assert!(err.to_string().contains("must be at least v0.7.0"));
     }
 }
@@ -1,23 +1,43 @@
 use std::{
-    collections::HashMap,
-    error::Error,
-    fmt, fs,
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    fs,
+    hash::Hasher,
+    io::{self, Read},
+    os::unix::fs::MetadataExt,
     path::{Path, PathBuf},
+    time::SystemTime,
 };
 
 use lazy_static::lazy_static;
 use path_clean::clean;
 
+use serde::{Deserialize, Serialize};
+use siphasher::sip::SipHasher24;
+
 use crate::{
     cache::{Cache, CdiOption},
-    spec::{read_spec, Spec},
-    utils::is_cdi_spec,
+    error::CdiError,
+    limits::{Limit, Limits},
+    spec::{read_spec_with_limits, restore_spec, Spec},
+    specs::config::Spec as CDISpec,
+    utils::{is_cdi_spec, rename_in},
 };
 
 // DEFAULT_STATIC_DIR is the default directory for static CDI Specs.
-const DEFAULT_STATIC_DIR: &str = "/etc/cdi";
+pub(crate) const DEFAULT_STATIC_DIR: &str = "/etc/cdi";
 // DEFAULT_DYNAMIC_DIR is the default directory for generated CDI Specs
 const DEFAULT_DYNAMIC_DIR: &str = "/var/run/cdi";
+// DEFAULT_SNAPSHOT_FILE is the suggested file for saving the Specs loaded
+// by a Cache, see with_snapshot_file.
+pub const DEFAULT_SNAPSHOT_FILE: &str = "/var/run/cdi/.cache";
+// SNAPSHOT_VERSION is the version of the snapshot file format.
+const SNAPSHOT_VERSION: u32 = 2;
+// FINGERPRINT_HASH names the hash of Spec file contents recorded in the
+// fingerprints of a snapshot file: SipHash-2-4 with both keys 0. It must
+// not change without changing the name, or snapshots written by another
+// build would compare hashes of different algorithms.
+const FINGERPRINT_HASH: &str = "siphash-2-4";
 
 lazy_static! {
     // DEFAULT_SPEC_DIRS is the default Spec directory configuration.
@@ -32,121 +52,554 @@ lazy_static! {
     ];
 }
 
-#[derive(Debug)]
-pub struct SpecError {
-    message: String,
+// SpecDir is a Spec directory together with the settings for scanning it.
+// The Specs found in the directory get its priority, which decides which
+// device is used if several Specs define the same device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpecDir {
+    pub path: String,
+    pub priority: i32,
+    // recursive also scans the subdirectories of the directory.
+    pub recursive: bool,
+    // follow_symlinks follows symbolic links to Spec files and, if
+    // recursive, to directories. Directories are only scanned once, so
+    // symlink loops are harmless.
+    pub follow_symlinks: bool,
+    // writable lets write_spec() and remove_spec() use the directory.
+    pub writable: bool,
 }
 
-impl SpecError {
-    pub fn new(info: &str) -> Self {
+impl SpecDir {
+    // new returns a recursive, writable SpecDir with the given priority,
+    // which follows symbolic links. The path is cleaned.
+    pub fn new(path: &str, priority: i32) -> Self {
         Self {
-            message: info.to_owned(),
+            path: path.to_owned(),
+            priority,
+            recursive: true,
+            follow_symlinks: true,
+            writable: true,
         }
+        .cleaned()
     }
-}
 
-impl fmt::Display for SpecError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "spec error message {}", self.message)
+    // cleaned returns the SpecDir with its path cleaned, so SpecDirs can be
+    // told apart by their paths.
+    pub(crate) fn cleaned(self) -> Self {
+        let path = clean(PathBuf::from(&self.path))
+            .into_os_string()
+            .into_string()
+            .unwrap();
+        Self { path, ..self }
     }
 }
 
-impl Error for SpecError {}
-
-pub fn convert_errors(
-    spec_errors: &HashMap<String, Vec<Box<dyn Error>>>,
-) -> HashMap<String, Vec<Box<dyn Error + Send + Sync + 'static>>> {
-    spec_errors
-        .iter()
-        .map(|(key, value)| {
-            (
-                key.clone(),
-                value
-                    .iter()
-                    .map(|error| {
-                        Box::new(SpecError::new(&error.to_string()))
-                            as Box<dyn Error + Send + Sync + 'static>
-                    })
-                    .collect(),
-            )
-        })
-        .collect()
-}
-
 /// with_spec_dirs returns an option to override the CDI Spec directories.
+/// The directories get their index in dirs as their priority.
 pub fn with_spec_dirs(dirs: &[&str]) -> CdiOption {
-    let cleaned_dirs: Vec<String> = dirs
+    let spec_dirs: Vec<SpecDir> = dirs
         .iter()
-        .map(|dir| {
-            clean(PathBuf::from(*dir))
-                .into_os_string()
-                .into_string()
-                .unwrap()
-        })
+        .enumerate()
+        .map(|(priority, dir)| SpecDir::new(dir, priority as i32))
         .collect();
 
     Box::new(move |cache: &mut Cache| {
-        cache.spec_dirs.clone_from(&cleaned_dirs);
+        cache.spec_dirs.clone_from(&spec_dirs);
     })
 }
 
-#[allow(dead_code)]
-fn traverse_dir<F>(dir_path: &Path, traverse_fn: &mut F) -> Result<(), Box<dyn Error>>
+// with_spec_dir returns an option to add a Spec directory with its own
+// settings, for instance a vendor drop-in directory with an explicit
+// priority. A Spec directory with the same path is replaced.
+pub fn with_spec_dir(dir: SpecDir) -> CdiOption {
+    let dir = dir.cleaned();
+
+    Box::new(move |cache: &mut Cache| {
+        match cache.spec_dirs.iter_mut().find(|d| d.path == dir.path) {
+            Some(existing) => *existing = dir,
+            None => cache.spec_dirs.push(dir),
+        }
+    })
+}
+
+// with_rootless_spec_dirs returns an option to use the default Spec
+// directories of the current user. For root these are DEFAULT_SPEC_DIRS.
+// Other users can't write the system directories and may not want to trust
+// them over their own Specs, so for them the Spec directories are, in
+// increasing order of priority,
+//   - /etc/cdi and /var/run/cdi, which are not written to,
+//   - $XDG_CONFIG_HOME/cdi, or ~/.config/cdi, for static Specs,
+//   - $XDG_RUNTIME_DIR/cdi, if set, for generated Specs.
+// The directories are looked up when the option is created.
+pub fn with_rootless_spec_dirs() -> CdiOption {
+    let spec_dirs = user_spec_dirs();
+
+    Box::new(move |cache: &mut Cache| {
+        cache.spec_dirs.clone_from(&spec_dirs);
+    })
+}
+
+// user_spec_dirs returns the default Spec directories of the current user,
+// see with_rootless_spec_dirs.
+pub(crate) fn user_spec_dirs() -> Vec<SpecDir> {
+    rootless_spec_dirs(effective_uid(), |name| std::env::var(name).ok())
+}
+
+// rootless_spec_dirs returns the Spec directories of the user euid, see
+// with_rootless_spec_dirs, looking up environment variables with env.
+fn rootless_spec_dirs<F>(euid: u32, env: F) -> Vec<SpecDir>
 where
-    F: FnMut(&Path) -> Result<(), Box<dyn Error>>,
+    F: Fn(&str) -> Option<String>,
 {
-    if let Ok(entries) = fs::read_dir(dir_path) {
-        for entry in entries.flatten() {
-            let path = entry.path();
-            if path.is_dir() {
-                traverse_dir(&path, traverse_fn)?;
-            } else {
-                traverse_fn(&path)?;
+    let mut dirs: Vec<SpecDir> = DEFAULT_SPEC_DIRS
+        .iter()
+        .enumerate()
+        .map(|(priority, dir)| SpecDir::new(dir, priority as i32))
+        .collect();
+    if euid == 0 {
+        return dirs;
+    }
+
+    for dir in dirs.iter_mut() {
+        dir.writable = false;
+    }
+    // XDG base directories must be absolute, others are ignored.
+    let xdg_dir = |name: &str| env(name).filter(|dir| Path::new(dir).is_absolute());
+    let config_home = xdg_dir("XDG_CONFIG_HOME").or_else(|| {
+        xdg_dir("HOME").map(|home| Path::new(&home).join(".config").display().to_string())
+    });
+    for base in [config_home, xdg_dir("XDG_RUNTIME_DIR")]
+        .into_iter()
+        .flatten()
+    {
+        let path = Path::new(&base).join("cdi");
+        dirs.push(SpecDir::new(&path.to_string_lossy(), dirs.len() as i32));
+    }
+
+    dirs
+}
+
+// traverse_dir calls traverse_fn for the files in the given Spec dir,
+// honouring its recursion and symlink settings. Subdirectories nested
+// deeper than the depth limit are not scanned, traverse_fn gets an error
+// for them instead.
+fn traverse_dir<F, E>(dir: &SpecDir, limits: &Limits, traverse_fn: &mut F) -> Result<(), E>
+where
+    F: FnMut(Result<&Path, CdiError>) -> Result<(), E>,
+{
+    let mut visited = HashSet::new();
+    if let Ok(meta) = fs::metadata(&dir.path) {
+        visited.insert((meta.dev(), meta.ino()));
+    }
+    traverse(
+        Path::new(&dir.path),
+        0,
+        dir,
+        limits,
+        &mut visited,
+        &mut |_| {},
+        traverse_fn,
+    )
+}
+
+// watched_paths returns the paths below the given Spec dir which need to
+// be watched, besides the dir itself, to notice all changes to what
+// traverse_dir scans: the subdirectories it enters and, since changing
+// their targets doesn't show up in the directory, symlinked Spec files.
+pub(crate) fn watched_paths(dir: &SpecDir, limits: &Limits) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+    let mut visited = HashSet::new();
+    if let Ok(meta) = fs::metadata(&dir.path) {
+        visited.insert((meta.dev(), meta.ino()));
+    }
+    let Ok(()) = traverse::<_, _, Infallible>(
+        Path::new(&dir.path),
+        0,
+        dir,
+        limits,
+        &mut visited,
+        &mut |path| dirs.push(path.to_path_buf()),
+        &mut |path| {
+            if let Ok(path) = path {
+                let symlink = fs::symlink_metadata(path).is_ok_and(|meta| meta.is_symlink());
+                if symlink && is_cdi_spec(path) {
+                    files.push(path.to_path_buf());
+                }
             }
+            Ok(())
+        },
+    );
+    dirs.extend(files);
+    dirs
+}
+
+fn traverse<D, F, E>(
+    dir_path: &Path,
+    depth: u64,
+    dir: &SpecDir,
+    limits: &Limits,
+    visited: &mut HashSet<(u64, u64)>,
+    dir_fn: &mut D,
+    traverse_fn: &mut F,
+) -> Result<(), E>
+where
+    D: FnMut(&Path),
+    F: FnMut(Result<&Path, CdiError>) -> Result<(), E>,
+{
+    let Ok(entries) = fs::read_dir(dir_path) else {
+        return Ok(());
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_symlink() && !dir.follow_symlinks {
+            continue;
+        }
+        let Ok(meta) = fs::metadata(&path) else {
+            continue;
+        };
+
+        if meta.is_dir() {
+            if !dir.recursive || !visited.insert((meta.dev(), meta.ino())) {
+                continue;
+            }
+            match limits.check(Limit::Depth, &path, depth + 1) {
+                Ok(()) => {
+                    dir_fn(&path);
+                    traverse(&path, depth + 1, dir, limits, visited, dir_fn, traverse_fn)?
+                }
+                Err(err) => traverse_fn(Err(err))?,
+            }
+        } else {
+            traverse_fn(Ok(&path))?;
         }
     }
     Ok(())
 }
 
+// effective_uid returns the effective user ID of the process.
+fn effective_uid() -> u32 {
+    // SAFETY: geteuid() has no preconditions and can't fail.
+    unsafe { libc::geteuid() }
+}
+
+// check_permissions checks that only root and the current user can modify
+// the given file. The file and all of its parent directories, both along
+// the given path and along the path it resolves to, must be owned by root
+// or the current user and must not be writable by group or others. World
+// writable directories with the sticky bit set, like /tmp, are accepted.
+pub(crate) fn check_permissions(path: &Path) -> Result<(), CdiError> {
+    let insecure = |reason: String| CdiError::InsecureSpec {
+        path: path.display().to_string(),
+        reason,
+    };
+    let resolved =
+        fs::canonicalize(path).map_err(|err| CdiError::invalid_spec(path, err.into()))?;
+    let euid = effective_uid();
+
+    for checked in path.ancestors().chain(resolved.ancestors()) {
+        if checked.as_os_str().is_empty() {
+            continue;
+        }
+        let meta = fs::metadata(checked).map_err(|err| CdiError::invalid_spec(path, err.into()))?;
+        let uid = meta.uid();
+        if uid != 0 && uid != euid {
+            return Err(insecure(format!(
+                "{} is owned by uid {}",
+                checked.display(),
+                uid
+            )));
+        }
+        let mode = meta.mode();
+        let sticky_dir = meta.is_dir() && mode & 0o1000 != 0;
+        if mode & 0o022 != 0 && !sticky_dir {
+            return Err(insecure(format!(
+                "{} is writable by group or others",
+                checked.display()
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// Fingerprint identifies the content of a Spec file, without parsing it.
+// Unless content hashing is enabled, the file is not even read and edits
+// which keep the modification time, size and inode are not noticed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Fingerprint {
+    modified: Option<SystemTime>,
+    size: u64,
+    dev: u64,
+    inode: u64,
+    hash: Option<u64>,
+}
+
+impl Fingerprint {
+    // new takes the fingerprint of the given file. At most max_size bytes
+    // of it are hashed, larger files are refused when they are parsed.
+    fn new(path: &Path, hash_content: bool, max_size: u64) -> io::Result<Self> {
+        let meta = fs::metadata(path)?;
+        let hash = if hash_content {
+            let mut hasher = SipHasher24::new_with_keys(0, 0);
+            let mut data = Vec::new();
+            fs::File::open(path)?
+                .take(max_size)
+                .read_to_end(&mut data)?;
+            hasher.write(&data);
+            Some(hasher.finish())
+        } else {
+            None
+        };
+
+        Ok(Self {
+            modified: meta.modified().ok(),
+            size: meta.len(),
+            dev: meta.dev(),
+            inode: meta.ino(),
+            hash,
+        })
+    }
+}
+
+// SpecFiles keeps the Specs loaded by the previous scan, together with the
+// fingerprints of their files. Files with an unchanged fingerprint reuse
+// their Spec, so rescanning unchanged Spec directories only stats files.
+#[derive(Default)]
+pub(crate) struct SpecFiles {
+    // hash_content adds a hash of the file content to the fingerprints.
+    pub(crate) hash_content: bool,
+    // limits bounds the Spec files loaded by a scan.
+    pub(crate) limits: Limits,
+    // secure refuses Spec files with unsafe ownership or permissions, see
+    // check_permissions().
+    pub(crate) secure: bool,
+    // ignored are the canonical paths of files which are never loaded as
+    // Spec files, see Cache::ignored_files.
+    pub(crate) ignored: Vec<PathBuf>,
+    // snapshot_file is restored before the first scan and saved after
+    // scans which changed the Specs, see with_snapshot_file.
+    pub(crate) snapshot_file: Option<PathBuf>,
+    // changed is set when a scan loads Specs differing from the previous
+    // scan, and cleared once they are saved to a snapshot file.
+    pub(crate) changed: bool,
+    // restored is set once a snapshot file has been tried to be loaded.
+    pub(crate) restored: bool,
+    loaded: HashMap<PathBuf, (Fingerprint, Spec)>,
+}
+
+// SnapshotFile is the content of a snapshot file.
+#[derive(Serialize, Deserialize)]
+struct SnapshotFile {
+    version: u32,
+    fingerprint_hash: String,
+    specs: Vec<SnapshotEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    path: PathBuf,
+    priority: i32,
+    fingerprint: Fingerprint,
+    spec: CDISpec,
+}
+
+impl SpecFiles {
+    // is_ignored checks if the given file is one of the ignored files. Only
+    // files with the name of an ignored file are resolved.
+    fn is_ignored(&self, path: &Path) -> bool {
+        self.ignored.iter().any(|ignored| {
+            ignored.file_name() == path.file_name()
+                && fs::canonicalize(path).is_ok_and(|path| path == *ignored)
+        })
+    }
+
+    // load returns the Spec for the given file, reusing the Spec of the
+    // previous scan if the file is unchanged. The fingerprint is taken
+    // before the file is read, so a concurrent update is seen next time.
+    fn load(
+        &mut self,
+        path: &Path,
+        priority: i32,
+        loaded: &mut HashMap<PathBuf, (Fingerprint, Spec)>,
+    ) -> Result<Spec, CdiError> {
+        // Permissions can change without changing the fingerprint, so they
+        // are checked on every scan.
+        if self.secure {
+            check_permissions(path)?;
+        }
+        let fingerprint = Fingerprint::new(path, self.hash_content, self.limits.max_file_size)
+            .map_err(|err| CdiError::invalid_spec(path, err.into()))?;
+        if let Some((previous, spec)) = self.loaded.get(path) {
+            if *previous == fingerprint && spec.get_priority() == priority {
+                let mut spec = spec.clone();
+                spec.set_modified(fingerprint.modified);
+                loaded.insert(path.to_path_buf(), (fingerprint, spec.clone()));
+                return Ok(spec);
+            }
+        }
+
+        let mut spec = read_spec_with_limits(&path.to_path_buf(), priority, &self.limits)?;
+        spec.set_modified(fingerprint.modified);
+        self.changed = true;
+        loaded.insert(path.to_path_buf(), (fingerprint, spec.clone()));
+        Ok(spec)
+    }
+
+    // restore loads the Specs saved to a snapshot file by save(), so the
+    // next scan only needs to load the Spec files which have changed since.
+    // A missing, corrupt or outdated snapshot file is not an error, the
+    // Spec files are then all loaded by the next scan.
+    pub(crate) fn restore(&mut self, file: &Path) -> bool {
+        self.restored = true;
+
+        let restored = || -> anyhow::Result<HashMap<PathBuf, (Fingerprint, Spec)>> {
+            let snapshot: SnapshotFile = serde_json::from_slice(&fs::read(file)?)?;
+            if snapshot.version != SNAPSHOT_VERSION {
+                return Err(anyhow::anyhow!(
+                    "unsupported snapshot version {}",
+                    snapshot.version
+                ));
+            }
+            if snapshot.fingerprint_hash != FINGERPRINT_HASH {
+                return Err(anyhow::anyhow!(
+                    "unsupported snapshot fingerprint hash {}",
+                    snapshot.fingerprint_hash
+                ));
+            }
+            snapshot
+                .specs
+                .into_iter()
+                .map(|entry| {
+                    let spec = restore_spec(&entry.spec, &entry.path, entry.priority)?;
+                    Ok((entry.path, (entry.fingerprint, spec)))
+                })
+                .collect()
+        };
+
+        match restored() {
+            Ok(loaded) => {
+                self.loaded = loaded;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    // save writes the Specs of the last scan to the given snapshot file.
+    // The file is replaced atomically, so concurrent restores never see
+    // a partially written snapshot.
+    pub(crate) fn save(&mut self, file: &Path) -> anyhow::Result<()> {
+        let mut specs: Vec<SnapshotEntry> = self
+            .loaded
+            .iter()
+            .map(|(path, (fingerprint, spec))| SnapshotEntry {
+                path: path.clone(),
+                priority: spec.get_priority(),
+                fingerprint: fingerprint.clone(),
+                spec: spec.cdi_spec.clone(),
+            })
+            .collect();
+        specs.sort_by(|a, b| a.path.cmp(&b.path));
+        let data = serde_json::to_vec(&SnapshotFile {
+            version: SNAPSHOT_VERSION,
+            fingerprint_hash: FINGERPRINT_HASH.to_string(),
+            specs,
+        })?;
+
+        let (dir, name) = match (file.parent(), file.file_name()) {
+            (Some(dir), Some(name)) => (dir, name),
+            _ => return Err(anyhow::anyhow!("invalid snapshot file {:?}", file)),
+        };
+        fs::create_dir_all(dir)?;
+        let tmp_name = format!("{}.{}.tmp", name.to_string_lossy(), std::process::id());
+        if let Err(e) = fs::write(dir.join(&tmp_name), data)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| rename_in(dir, Path::new(&tmp_name), Path::new(name), true))
+        {
+            let _ = fs::remove_file(dir.join(&tmp_name));
+            return Err(e.context("failed to save snapshot file"));
+        }
+
+        self.changed = false;
+        Ok(())
+    }
+}
+
 // scan_spec_dirs scans the given directories looking for CDI Spec files,
 // which are all files with a '.json' or '.yaml' suffix. For every Spec
 // file discovered, if it's a cdi spec, then loads a Spec from the file
-// with the priority (the index of the directory in the slice of directories given),
-// then collect the CDI Specs, and any error encountered while loading the Spec return Error.
+// with the priority of the directory, then collect the CDI Specs. A Spec
+// file which fails to load does not stop the scan: its error is collected,
+// recording the path of the file, and the scan goes on with the next file.
+// Both the loaded Specs and the errors are returned.
 #[allow(dead_code)]
-pub(crate) fn scan_spec_dirs<P: AsRef<Path>>(dirs: &[P]) -> Result<Vec<Spec>, Box<dyn Error>> {
+pub(crate) fn scan_spec_dirs(dirs: &[SpecDir]) -> (Vec<Spec>, Vec<CdiError>) {
+    rescan_spec_dirs(dirs, &mut SpecFiles::default())
+}
+
+// rescan_spec_dirs scans the given directories like scan_spec_dirs, but
+// only loads the Spec files which have changed since the previous scan
+// recorded in files. Files is updated to reflect the current scan.
+pub(crate) fn rescan_spec_dirs(
+    dirs: &[SpecDir],
+    files: &mut SpecFiles,
+) -> (Vec<Spec>, Vec<CdiError>) {
+    let mut loaded = HashMap::new();
     let mut scaned_specs = Vec::new();
-    for (priority, dir) in dirs.iter().enumerate() {
-        let dir_path = dir.as_ref();
-        if !dir_path.is_dir() {
+    let mut scan_errors = Vec::new();
+    let mut spec_files = 0;
+    let limits = files.limits;
+    for dir in dirs {
+        if !Path::new(&dir.path).is_dir() {
             continue;
         }
 
-        let mut operation = |path: &Path| -> Result<(), Box<dyn Error>> {
-            if !path.is_dir() && is_cdi_spec(path) {
-                let spec = match read_spec(&path.to_path_buf(), priority as i32) {
-                    Ok(spec) => spec,
-                    Err(err) => {
-                        return Err(Box::new(SpecError::new(&err.to_string())));
-                    }
-                };
-                scaned_specs.push(spec);
+        let mut operation = |path: Result<&Path, CdiError>| -> Result<(), Infallible> {
+            let path = match path {
+                Ok(path) => path,
+                Err(err) => {
+                    scan_errors.push(err);
+                    return Ok(());
+                }
+            };
+            if !is_cdi_spec(path) || files.is_ignored(path) {
+                return Ok(());
+            }
+            // Only the first Spec file over the limit gets an error.
+            spec_files += 1;
+            if let Err(err) = limits.check(Limit::SpecFiles, path, spec_files) {
+                if spec_files == limits.max_spec_files + 1 {
+                    scan_errors.push(err);
+                }
+                return Ok(());
+            }
+            match files.load(path, dir.priority, &mut loaded) {
+                Ok(spec) => scaned_specs.push(spec),
+                Err(err) => scan_errors.push(err),
             }
             Ok(())
         };
 
-        if let Err(e) = traverse_dir(dir_path, &mut operation) {
-            return Err(Box::new(SpecError::new(&e.to_string())));
-        }
+        let Ok(()) = traverse_dir(dir, &limits, &mut operation);
     }
 
-    Ok(scaned_specs)
+    if loaded.len() != files.loaded.len() {
+        files.changed = true;
+    }
+    files.loaded = loaded;
+    (scaned_specs, scan_errors)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::collections::HashMap;
+    use std::error::Error;
     use std::fs;
 
     const SPEC_YAML: &str = r#"cdiVersion: "0.6.0"
@@ -166,25 +619,22 @@ devices:
   ]
 }"#;
 
+    // dirs returns SpecDirs for the given paths, with their index as the priority.
+    fn dirs<P: AsRef<Path>>(paths: &[P]) -> Vec<SpecDir> {
+        paths
+            .iter()
+            .enumerate()
+            .map(|(i, p)| SpecDir::new(p.as_ref().to_str().unwrap(), i as i32))
+            .collect()
+    }
+
     #[test]
     fn with_spec_dirs_cleans_paths() {
         let mut cache = Cache::default();
         with_spec_dirs(&["/etc/cdi/../cdi", "/var/run/cdi/"])(&mut cache);
-        assert_eq!(cache.spec_dirs, vec!["/etc/cdi", "/var/run/cdi"]);
-    }
-
-    #[test]
-    fn spec_error_display_and_conversion() {
-        let mut errors: HashMap<String, Vec<Box<dyn Error>>> = HashMap::new();
-        errors.insert(
-            "/etc/cdi/broken.yaml".to_string(),
-            vec![Box::new(SpecError::new("bad spec"))],
-        );
-
-        let converted = convert_errors(&errors);
-        let msgs = &converted["/etc/cdi/broken.yaml"];
-        assert_eq!(msgs.len(), 1);
-        assert!(msgs[0].to_string().contains("bad spec"));
+        let paths: Vec<&str> = cache.spec_dirs.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(paths, vec!["/etc/cdi", "/var/run/cdi"]);
+        assert_eq!(cache.spec_dirs[1].priority, 1);
     }
 
     #[test]
@@ -199,8 +649,9 @@ devices:
         // non-spec files are ignored
         fs::write(high.path().join("README.txt"), "not a spec").unwrap();
 
-        let specs = scan_spec_dirs(&[low.path(), high.path()]).unwrap();
+        let (specs, errors) = scan_spec_dirs(&dirs(&[low.path(), high.path()]));
 
+        assert!(errors.is_empty());
         assert_eq!(specs.len(), 2);
         let by_vendor: HashMap<_, _> = specs
             .iter()
@@ -212,14 +663,380 @@ devices:
 
     #[test]
     fn scan_skips_missing_dirs() {
-        let specs = scan_spec_dirs(&["/nonexistent/cdi/dir"]).unwrap();
+        let (specs, errors) = scan_spec_dirs(&dirs(&["/nonexistent/cdi/dir"]));
         assert!(specs.is_empty());
+        assert!(errors.is_empty());
     }
 
     #[test]
-    fn scan_fails_on_invalid_spec() {
+    fn scan_keeps_good_specs_next_to_invalid_ones() {
         let dir = tempfile::tempdir().unwrap();
         fs::write(dir.path().join("broken.yaml"), "cdiVersion: [not a spec").unwrap();
-        assert!(scan_spec_dirs(&[dir.path()]).is_err());
+        fs::write(dir.path().join("vendor.yaml"), SPEC_YAML).unwrap();
+        fs::write(dir.path().join("vendor2.json"), SPEC_JSON).unwrap();
+
+        let (specs, errors) = scan_spec_dirs(&dirs(&[dir.path()]));
+
+        let mut vendors: Vec<_> = specs.iter().map(|s| s.get_vendor().to_string()).collect();
+        vendors.sort();
+        assert_eq!(vendors, vec!["vendor.com", "vendor2.com"]);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn rescan_reparses_only_changed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vendor.yaml");
+        fs::write(&path, SPEC_YAML).unwrap();
+        let env = |specs: &[Spec]| specs[0].cdi_spec.devices[0].container_edits.env.clone();
+
+        let mut files = SpecFiles::default();
+        let (specs, _) = rescan_spec_dirs(&dirs(&[dir.path()]), &mut files);
+        assert_eq!(env(&specs), Some(vec!["VENDOR=1".to_string()]));
+
+        // Same size, inode and modification time: the old Spec is reused.
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        fs::write(&path, SPEC_YAML.replace("VENDOR=1", "VENDOR=2")).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let (specs, _) = rescan_spec_dirs(&dirs(&[dir.path()]), &mut files);
+        assert_eq!(env(&specs), Some(vec!["VENDOR=1".to_string()]));
+
+        // Hashing the content notices the change.
+        files.hash_content = true;
+        let (specs, _) = rescan_spec_dirs(&dirs(&[dir.path()]), &mut files);
+        assert_eq!(env(&specs), Some(vec!["VENDOR=2".to_string()]));
+
+        // A changed priority needs a new Spec too.
+        let other = tempfile::tempdir().unwrap();
+        let (specs, _) = rescan_spec_dirs(&dirs(&[other.path(), dir.path()]), &mut files);
+        assert_eq!(specs[0].get_priority(), 1);
+
+        fs::remove_file(&path).unwrap();
+        let (specs, _) = rescan_spec_dirs(&dirs(&[dir.path()]), &mut files);
+        assert!(specs.is_empty());
+        assert!(files.loaded.is_empty());
+    }
+
+    #[test]
+    fn snapshot_restores_unchanged_specs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vendor.yaml");
+        let snapshot = dir.path().join("snapshot").join(".cache");
+        fs::write(&path, SPEC_YAML).unwrap();
+        let env = |specs: &[Spec]| specs[0].cdi_spec.devices[0].container_edits.env.clone();
+
+        let mut files = SpecFiles::default();
+        assert!(!files.restore(&snapshot));
+        rescan_spec_dirs(&dirs(&[dir.path()]), &mut files);
+        assert!(files.changed);
+        files.save(&snapshot).unwrap();
+        assert!(!files.changed);
+
+        // An unchanged file is taken from the snapshot instead of reparsed.
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        fs::write(&path, SPEC_YAML.replace("VENDOR=1", "VENDOR=2")).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let mut files = SpecFiles::default();
+        assert!(files.restore(&snapshot));
+        let (specs, _) = rescan_spec_dirs(&dirs(&[dir.path()]), &mut files);
+        assert_eq!(env(&specs), Some(vec!["VENDOR=1".to_string()]));
+        assert!(!files.changed);
+
+        // A corrupt snapshot is a miss, so every file is loaded.
+        fs::write(&snapshot, "{\"version\": 2, \"specs\": [").unwrap();
+        let mut files = SpecFiles::default();
+        assert!(!files.restore(&snapshot));
+        let (specs, _) = rescan_spec_dirs(&dirs(&[dir.path()]), &mut files);
+        assert_eq!(env(&specs), Some(vec!["VENDOR=2".to_string()]));
+
+        // So is a snapshot of another version, or hashing file contents
+        // with another algorithm.
+        fs::write(&snapshot, "{\"version\": 1, \"specs\": []}").unwrap();
+        assert!(!SpecFiles::default().restore(&snapshot));
+        fs::write(
+            &snapshot,
+            "{\"version\": 2, \"fingerprint_hash\": \"sha256\", \"specs\": []}",
+        )
+        .unwrap();
+        assert!(!SpecFiles::default().restore(&snapshot));
+    }
+
+    #[test]
+    fn fingerprint_hashes_are_stable() {
+        // Snapshots persist the hashes, so they may not depend on the
+        // build: this is the SipHash-2-4 test vector of the empty input.
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let fingerprint = Fingerprint::new(file.path(), true, 1024).unwrap();
+        assert_eq!(fingerprint.hash, Some(0x1e924b9d737700d7));
+    }
+
+    #[test]
+    fn scan_honours_recursion_and_symlink_settings() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("cdi");
+        let nested = dir.join("nested");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(dir.join("vendor.yaml"), SPEC_YAML).unwrap();
+        fs::write(nested.join("vendor2.json"), SPEC_JSON).unwrap();
+        // A symlink loop, and a symlink to a Spec outside of the dir.
+        std::os::unix::fs::symlink(&dir, nested.join("loop")).unwrap();
+        let outside = root.path().join("outside.yaml");
+        fs::write(&outside, SPEC_YAML.replace("vendor.com", "outside.com")).unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("outside.yaml")).unwrap();
+
+        let kinds = |spec_dir: SpecDir| {
+            let (specs, errors) = scan_spec_dirs(&[spec_dir]);
+            assert!(errors.is_empty());
+            let mut kinds: Vec<String> = specs.iter().map(|s| s.cdi_spec.kind.clone()).collect();
+            kinds.sort();
+            kinds
+        };
+
+        let spec_dir = SpecDir::new(dir.to_str().unwrap(), 7);
+        assert_eq!(
+            kinds(spec_dir.clone()),
+            vec![
+                "outside.com/device",
+                "vendor.com/device",
+                "vendor2.com/device"
+            ]
+        );
+        assert_eq!(
+            kinds(SpecDir {
+                recursive: false,
+                ..spec_dir.clone()
+            }),
+            vec!["outside.com/device", "vendor.com/device"]
+        );
+        assert_eq!(
+            kinds(SpecDir {
+                follow_symlinks: false,
+                ..spec_dir.clone()
+            }),
+            vec!["vendor.com/device", "vendor2.com/device"]
+        );
+
+        let (specs, _) = scan_spec_dirs(&[spec_dir]);
+        assert!(specs.iter().all(|spec| spec.get_priority() == 7));
+    }
+
+    #[test]
+    fn check_permissions_follows_symlinks() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("cdi");
+        let shared = root.path().join("shared");
+        fs::create_dir_all(&dir).unwrap();
+        fs::create_dir_all(&shared).unwrap();
+        let target = shared.join("vendor.yaml");
+        fs::write(&target, SPEC_YAML).unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o644)).unwrap();
+        let link = dir.join("vendor.yaml");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        check_permissions(&link).unwrap();
+
+        // A writable directory on the resolved path makes the Spec unsafe.
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o775)).unwrap();
+        let err = check_permissions(&link).unwrap_err();
+        assert!(matches!(err, CdiError::InsecureSpec { .. }), "{err:?}");
+        assert!(err.to_string().contains(shared.to_str().unwrap()));
+
+        // World writable directories are fine with the sticky bit set.
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o1777)).unwrap();
+        check_permissions(&link).unwrap();
+    }
+
+    #[test]
+    fn scan_enforces_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("a").join("b");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(dir.path().join("1.yaml"), SPEC_YAML).unwrap();
+        fs::write(
+            dir.path().join("2.yaml"),
+            SPEC_YAML.replace("vendor.com", "other.com"),
+        )
+        .unwrap();
+        fs::write(nested.join("deep.yaml"), SPEC_YAML).unwrap();
+
+        let scan = |limits: Limits| {
+            let mut files = SpecFiles {
+                limits,
+                ..Default::default()
+            };
+            rescan_spec_dirs(&dirs(&[dir.path()]), &mut files)
+        };
+        let exceeded = |errors: &[CdiError]| -> Vec<(String, Limit)> {
+            errors
+                .iter()
+                .map(|err| match err {
+                    CdiError::LimitExceeded { path, limit, .. } => (path.clone(), *limit),
+                    err => panic!("unexpected error {err:?}"),
+                })
+                .collect()
+        };
+
+        let (specs, errors) = scan(Limits {
+            max_depth: 1,
+            ..Default::default()
+        });
+        assert_eq!(specs.len(), 2);
+        let deep = nested.display().to_string();
+        assert_eq!(exceeded(&errors), vec![(deep, Limit::Depth)]);
+
+        let (specs, errors) = scan(Limits {
+            max_spec_files: 1,
+            ..Default::default()
+        });
+        assert_eq!(specs.len(), 1);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(exceeded(&errors)[0].1, Limit::SpecFiles);
+
+        let (specs, errors) = scan(Limits {
+            max_file_size: 16,
+            ..Default::default()
+        });
+        assert!(specs.is_empty());
+        assert_eq!(errors.len(), 3);
+        assert!(exceeded(&errors).iter().all(|(_, l)| *l == Limit::FileSize));
+
+        let (_, errors) = scan(Limits {
+            max_devices: 0,
+            ..Default::default()
+        });
+        assert_eq!(errors.len(), 3);
+        assert!(exceeded(&errors).iter().all(|(_, l)| *l == Limit::Devices));
+
+        fs::write(
+            dir.path().join("1.yaml"),
+            SPEC_YAML.replace("containerEdits:", "containerEdits: &edits") + "\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("3.yaml"),
+            r#"cdiVersion: "0.6.0"
+kind: "alias.com/device"
+devices:
+  - name: "gpu0"
+    containerEdits: &edits
+      env: ["A=1"]
+  - name: "gpu1"
+    containerEdits: *edits
+"#,
+        )
+        .unwrap();
+        let (specs, errors) = scan(Limits {
+            max_yaml_aliases: 0,
+            ..Default::default()
+        });
+        assert_eq!(specs.len(), 3);
+        let path = dir.path().join("3.yaml").display().to_string();
+        assert_eq!(exceeded(&errors), vec![(path, Limit::YamlAliases)]);
+    }
+
+    #[test]
+    fn rootless_spec_dirs_add_the_xdg_dirs() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(k, _)| *k == name)
+                    .map(|(_, v)| v.to_string())
+            }
+        };
+        let summary = |dirs: Vec<SpecDir>| -> Vec<(String, i32, bool)> {
+            dirs.into_iter()
+                .map(|d| (d.path, d.priority, d.writable))
+                .collect()
+        };
+
+        assert_eq!(
+            summary(rootless_spec_dirs(
+                0,
+                env(&[("XDG_RUNTIME_DIR", "/run/user/0")])
+            )),
+            vec![
+                ("/etc/cdi".to_string(), 0, true),
+                ("/var/run/cdi".to_string(), 1, true),
+            ]
+        );
+
+        assert_eq!(
+            summary(rootless_spec_dirs(
+                1000,
+                env(&[
+                    ("HOME", "/home/user"),
+                    ("XDG_CONFIG_HOME", "/home/user/.cfg/"),
+                    ("XDG_RUNTIME_DIR", "/run/user/1000"),
+                ])
+            )),
+            vec![
+                ("/etc/cdi".to_string(), 0, false),
+                ("/var/run/cdi".to_string(), 1, false),
+                ("/home/user/.cfg/cdi".to_string(), 2, true),
+                ("/run/user/1000/cdi".to_string(), 3, true),
+            ]
+        );
+
+        // Without XDG variables the config dir falls back to ~/.config,
+        // relative paths are ignored.
+        assert_eq!(
+            summary(rootless_spec_dirs(
+                1000,
+                env(&[("HOME", "/home/user"), ("XDG_RUNTIME_DIR", "run/user")])
+            ))
+            .into_iter()
+            .map(|(path, _, _)| path)
+            .collect::<Vec<_>>(),
+            vec!["/etc/cdi", "/var/run/cdi", "/home/user/.config/cdi"]
+        );
+    }
+
+    #[test]
+    fn with_spec_dir_adds_or_replaces_dirs() {
+        let mut cache = Cache::default();
+        with_spec_dirs(&["/etc/cdi", "/var/run/cdi"])(&mut cache);
+        with_spec_dir(SpecDir {
+            writable: false,
+            ..SpecDir::new("/opt/vendor/cdi/", 5)
+        })(&mut cache);
+        with_spec_dir(SpecDir::new("/etc/cdi", 3))(&mut cache);
+
+        let dirs: Vec<(&str, i32)> = cache
+            .spec_dirs
+            .iter()
+            .map(|d| (d.path.as_str(), d.priority))
+            .collect();
+        assert_eq!(
+            dirs,
+            vec![("/etc/cdi", 3), ("/var/run/cdi", 1), ("/opt/vendor/cdi", 5)]
+        );
+    }
+
+    #[test]
+    fn scan_errors_record_the_path_and_cause() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("broken.yaml");
+        fs::write(&path, "cdiVersion: [not a spec").unwrap();
+
+        let (specs, errors) = scan_spec_dirs(&dirs(&[dir.path()]));
+        assert!(specs.is_empty());
+        let err = &errors[0];
+
+        assert!(matches!(err, CdiError::InvalidSpec { .. }));
+        assert_eq!(err.get_path(), path.to_str());
+        assert!(err.to_string().contains("invalid CDI Spec"));
+        let cause = err.source().unwrap();
+        assert!(cause.to_string().contains("parse spec file failed"));
     }
 }
@@ -0,0 +1,379 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+
+use crate::{
+    cache::{Cache, CdiOption},
+    error::CdiError,
+    limits::{Limit, Limits},
+    spec::{new_source_spec, Spec},
+    spec_dirs::{check_permissions, rescan_spec_dirs, SpecDir, SpecFiles},
+    specs::config::Spec as CDISpec,
+};
+
+// RawSpec is CDI Spec data provided by a SpecSource. The origin labels
+// where the data comes from and is used as it is for the path of the Spec,
+// so it shows up in errors and in Cache::get_spec_errors(). It needn't be
+// a file path: ConflictPolicy::NewestMtime only uses the modification time
+// of Specs loaded from files by the Cache itself. Like the priority
+// of a Spec directory, the priority is used to resolve conflicting
+// devices.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RawSpec {
+    pub spec: CDISpec,
+    pub origin: String,
+    pub priority: i32,
+}
+
+impl RawSpec {
+    pub fn new(spec: CDISpec, origin: &str, priority: i32) -> Self {
+        Self {
+            spec,
+            origin: origin.to_owned(),
+            priority,
+        }
+    }
+}
+
+// SpecSource provides Specs to a Cache in addition to the Specs in its
+// Spec directories. Sources are loaded on every refresh of the Cache,
+// they are not watched: a source which changes needs an explicit
+// Cache::refresh() to make the change visible.
+pub trait SpecSource: Send + Sync {
+    // load returns the Specs of the source. A Spec which fails to load
+    // does not stop the others, its error is returned with them.
+    fn load(&self) -> (Vec<RawSpec>, Vec<CdiError>);
+}
+
+impl<S: SpecSource + ?Sized> SpecSource for Arc<S> {
+    fn load(&self) -> (Vec<RawSpec>, Vec<CdiError>) {
+        (**self).load()
+    }
+}
+
+// with_spec_source returns an option to add a SpecSource to the Cache.
+// Pass an Arc of the source to keep a handle to it.
+pub fn with_spec_source<S: SpecSource + 'static>(source: S) -> CdiOption {
+    let source: Arc<dyn SpecSource> = Arc::new(source);
+    Box::new(move |c: &mut Cache| {
+        c.spec_sources.push(source);
+    })
+}
+
+// DirSpecSource loads the Spec files in a list of Spec directories. A Cache
+// loads its own Spec directories with one, too. Unchanged Spec files are
+// only parsed once.
+#[derive(Default)]
+pub struct DirSpecSource {
+    dirs: Vec<SpecDir>,
+    // files keeps the Spec files loaded by the last scan. Its lock
+    // serializes scans.
+    files: Mutex<SpecFiles>,
+}
+
+impl DirSpecSource {
+    pub fn new(dirs: Vec<SpecDir>) -> Self {
+        Self {
+            dirs,
+            files: Mutex::new(SpecFiles::default()),
+        }
+    }
+
+    // limits sets the limits for loading Spec files, see with_limits.
+    pub fn limits(self, limits: Limits) -> Self {
+        self.files.lock().unwrap().limits = limits;
+        self
+    }
+
+    // secure_spec_files enables refusing Spec files unprivileged users
+    // could modify, see with_secure_spec_files.
+    pub fn secure_spec_files(self, secure: bool) -> Self {
+        self.files.lock().unwrap().secure = secure;
+        self
+    }
+
+    // scan loads the Spec files in the given dirs, after configure has
+    // adjusted the settings of the scan. With a snapshot file, the first
+    // scan restores the Specs saved by an earlier one, so only the Spec
+    // files which have changed since are parsed.
+    pub(crate) fn scan<F>(&self, dirs: &[SpecDir], configure: F) -> (Vec<Spec>, Vec<CdiError>)
+    where
+        F: FnOnce(&mut SpecFiles),
+    {
+        let mut files = self.files.lock().unwrap();
+        configure(&mut files);
+
+        let snapshot_file = files.snapshot_file.clone();
+        if let Some(file) = snapshot_file.as_deref() {
+            let trusted = || !files.secure || check_permissions(file).is_ok();
+            if !files.restored && trusted() {
+                files.restore(file);
+            }
+        }
+        let scanned = rescan_spec_dirs(dirs, &mut files);
+        // The snapshot file is only an optimization, failing to save it
+        // does not fail the scan.
+        if let Some(file) = snapshot_file.as_deref() {
+            if files.changed {
+                let _ = files.save(file);
+            }
+        }
+        scanned
+    }
+}
+
+impl SpecSource for DirSpecSource {
+    fn load(&self) -> (Vec<RawSpec>, Vec<CdiError>) {
+        let (specs, errors) = self.scan(&self.dirs, |_| {});
+        let specs = specs
+            .into_iter()
+            .map(|spec| RawSpec {
+                origin: spec.get_path(),
+                priority: spec.get_priority(),
+                spec: spec.cdi_spec,
+            })
+            .collect();
+        (specs, errors)
+    }
+}
+
+// StaticSpecSource provides a fixed list of Specs.
+#[derive(Clone, Debug, Default)]
+pub struct StaticSpecSource {
+    specs: Vec<RawSpec>,
+}
+
+impl StaticSpecSource {
+    pub fn new(specs: Vec<RawSpec>) -> Self {
+        Self { specs }
+    }
+}
+
+impl SpecSource for StaticSpecSource {
+    fn load(&self) -> (Vec<RawSpec>, Vec<CdiError>) {
+        (self.specs.clone(), Vec::new())
+    }
+}
+
+// FnSpecSource provides the Specs returned by a closure, for instance
+// Specs generated in-process by a device manager. An error of the
+// closure is reported for the given name.
+pub struct FnSpecSource<F> {
+    name: String,
+    load_fn: F,
+}
+
+impl<F> FnSpecSource<F>
+where
+    F: Fn() -> Result<Vec<RawSpec>> + Send + Sync,
+{
+    pub fn new(name: &str, load_fn: F) -> Self {
+        Self {
+            name: name.to_owned(),
+            load_fn,
+        }
+    }
+}
+
+impl<F> SpecSource for FnSpecSource<F>
+where
+    F: Fn() -> Result<Vec<RawSpec>> + Send + Sync,
+{
+    fn load(&self) -> (Vec<RawSpec>, Vec<CdiError>) {
+        match (self.load_fn)() {
+            Ok(specs) => (specs, Vec::new()),
+            Err(err) => (
+                Vec::new(),
+                vec![CdiError::invalid_spec(
+                    Path::new(&self.name),
+                    err.context("failed to load Specs"),
+                )],
+            ),
+        }
+    }
+}
+
+// load_spec_sources loads and validates the Specs of the given sources.
+// Their device count is checked against the limits like for Spec files.
+pub(crate) fn load_spec_sources(
+    sources: &[Arc<dyn SpecSource>],
+    limits: &Limits,
+) -> (Vec<Spec>, Vec<CdiError>) {
+    let mut specs = Vec::new();
+    let mut errors = Vec::new();
+    for source in sources {
+        let (raw_specs, source_errors) = source.load();
+        errors.extend(source_errors);
+        for raw in raw_specs {
+            let origin = Path::new(&raw.origin);
+            let spec = limits
+                .check(Limit::Devices, origin, raw.spec.devices.len() as u64)
+                .and_then(|_| new_source_spec(&raw.spec, &raw.origin, raw.priority));
+            match spec {
+                Ok(spec) => specs.push(spec),
+                Err(err) => errors.push(err),
+            }
+        }
+    }
+    (specs, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cache::ConflictPolicy, test_utils::raw_spec};
+    use anyhow::anyhow;
+    use std::{
+        fs,
+        sync::atomic::{AtomicBool, Ordering},
+        time::{Duration, SystemTime},
+    };
+
+    #[test]
+    fn cache_combines_spec_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("vendor.yaml"),
+            serde_yaml::to_string(&raw_spec("vendor.com/device", "FROM=dir")).unwrap(),
+        )
+        .unwrap();
+
+        let generated = StaticSpecSource::new(vec![RawSpec::new(
+            raw_spec("vendor.com/device", "FROM=static"),
+            "device-manager/vendor.yaml",
+            1,
+        )]);
+        let failing = Arc::new(AtomicBool::new(false));
+        let fail = failing.clone();
+        let dynamic = FnSpecSource::new("device-manager/dynamic", move || {
+            if fail.load(Ordering::SeqCst) {
+                return Err(anyhow!("device manager unavailable"));
+            }
+            Ok(vec![RawSpec::new(
+                raw_spec("other.com/device", "FROM=fn"),
+                "device-manager/other.yaml",
+                0,
+            )])
+        });
+
+        let cache = Cache::builder()
+            .spec_dirs(&[dir.path().to_str().unwrap()])
+            .auto_refresh(false)
+            .spec_source(generated)
+            .spec_source(dynamic)
+            .build()
+            .unwrap();
+        assert_eq!(
+            cache.list_devices(),
+            vec!["other.com/device=gpu0", "vendor.com/device=gpu0"]
+        );
+        // The static source has the higher priority.
+        let dev = cache.get_device("vendor.com/device=gpu0").unwrap();
+        assert_eq!(dev.get_spec().get_path(), "device-manager/vendor.yaml");
+
+        failing.store(true, Ordering::SeqCst);
+        let err = cache.refresh().unwrap_err();
+        assert_eq!(err.get_path(), Some("device-manager/dynamic"));
+        assert_eq!(cache.list_devices(), vec!["vendor.com/device=gpu0"]);
+    }
+
+    #[test]
+    fn sources_work_without_spec_dirs() {
+        let mut invalid = RawSpec::new(raw_spec("vendor.com/device", "A=1"), "memory/bad", 0);
+        invalid.spec.devices.clear();
+        let source = Arc::new(StaticSpecSource::new(vec![
+            RawSpec::new(
+                raw_spec("vendor.com/device", "A=1"),
+                "memory/vendor.yaml",
+                0,
+            ),
+            invalid,
+        ]));
+
+        let err = Cache::builder()
+            .spec_dirs(&[])
+            .auto_refresh(false)
+            .spec_source(source.clone())
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(err.get_path(), Some("memory/bad"), "{err}");
+
+        let mut cache = Cache::default();
+        with_spec_source(source)(&mut cache);
+        assert!(cache.refresh().is_err());
+        assert_eq!(cache.list_devices(), vec!["vendor.com/device=gpu0"]);
+        assert_eq!(
+            cache.get_errors().keys().collect::<Vec<_>>(),
+            vec!["memory/bad"]
+        );
+        let spec = &cache.get_vendor_specs("vendor.com")[0];
+        assert_eq!(spec.get_path(), "memory/vendor.yaml");
+        assert_eq!(spec.get_modified(), None);
+    }
+
+    #[test]
+    fn newest_mtime_does_not_stat_origins() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("vendor.yaml"),
+            serde_yaml::to_string(&raw_spec("vendor.com/device", "FROM=dir")).unwrap(),
+        )
+        .unwrap();
+        // A newer file which happens to be named like the origin.
+        let decoy = tempfile::NamedTempFile::new().unwrap();
+        decoy
+            .as_file()
+            .set_modified(SystemTime::now() + Duration::from_secs(3600))
+            .unwrap();
+
+        let source = StaticSpecSource::new(vec![RawSpec::new(
+            raw_spec("vendor.com/device", "FROM=static"),
+            decoy.path().to_str().unwrap(),
+            1,
+        )]);
+        let cache = Cache::builder()
+            .spec_dirs(&[dir.path().to_str().unwrap()])
+            .auto_refresh(false)
+            .conflict_policy(ConflictPolicy::NewestMtime)
+            .spec_source(source)
+            .build()
+            .unwrap();
+        let dev = cache.get_device("vendor.com/device=gpu0").unwrap();
+        assert_eq!(
+            dev.cdi_device.container_edits.env,
+            Some(vec!["FROM=dir".to_string()])
+        );
+    }
+
+    #[test]
+    fn dir_spec_source_loads_spec_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vendor.yaml");
+        fs::write(
+            &path,
+            serde_yaml::to_string(&raw_spec("vendor.com/device", "A=1")).unwrap(),
+        )
+        .unwrap();
+
+        let source = DirSpecSource::new(vec![SpecDir::new(dir.path().to_str().unwrap(), 3)]);
+        let (specs, errors) = source.load();
+        assert!(errors.is_empty(), "{errors:?}");
+        assert_eq!(specs.len(), 1);
+        assert_eq!(specs[0].origin, path.to_str().unwrap());
+        assert_eq!(specs[0].priority, 3);
+        assert_eq!(specs[0].spec.kind, "vendor.com/device");
+
+        let source = DirSpecSource::new(vec![SpecDir::new(dir.path().to_str().unwrap(), 0)])
+            .limits(Limits {
+                max_file_size: 8,
+                ..Default::default()
+            });
+        let (specs, errors) = source.load();
+        assert!(specs.is_empty());
+        assert!(matches!(errors[0], CdiError::LimitExceeded { .. }));
+    }
+}
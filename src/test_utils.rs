@@ -0,0 +1,31 @@
+// Fixtures shared by the unit tests of the crate.
+
+use crate::{cache::Cache, spec_dirs::with_spec_dirs, specs::config::Spec as CDISpec};
+
+// spec_yaml returns a Spec of the given kind with a single device, gpu0,
+// which sets the environment variable env.
+pub(crate) fn spec_yaml(kind: &str, env: &str) -> String {
+    format!(
+        r#"cdiVersion: "0.6.0"
+kind: "{kind}"
+devices:
+  - name: "gpu0"
+    containerEdits:
+      env:
+        - "{env}"
+"#
+    )
+}
+
+// raw_spec returns spec_yaml(kind, env) parsed, as passed to write_spec().
+pub(crate) fn raw_spec(kind: &str, env: &str) -> CDISpec {
+    serde_yaml::from_str(&spec_yaml(kind, env)).unwrap()
+}
+
+// dir_cache returns a Cache for the given Spec directories. It is not
+// refreshed yet.
+pub(crate) fn dir_cache(dirs: &[&str]) -> Cache {
+    let mut cache = Cache::default();
+    with_spec_dirs(dirs)(&mut cache);
+    cache
+}
@@ -0,0 +1,364 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{
+    cache::Cache, error::CdiError, spec::generate_name_for_transient_spec,
+    spec_dirs::DEFAULT_STATIC_DIR, specs::config::Spec as CDISpec,
+};
+
+// TRANSIENT_CLAIM_ANNOTATION records the UID of the resource claim
+// a transient Spec was generated for.
+pub const TRANSIENT_CLAIM_ANNOTATION: &str = "transient.cdi.k8s.io/claim-uid";
+// TRANSIENT_EXPIRES_ANNOTATION records when a transient Spec expires,
+// in seconds since the Unix epoch.
+pub const TRANSIENT_EXPIRES_ANNOTATION: &str = "transient.cdi.k8s.io/expires";
+// TRANSIENT_PID_ANNOTATION records the PID of the process a transient
+// Spec was generated by.
+pub const TRANSIENT_PID_ANNOTATION: &str = "transient.cdi.k8s.io/pid";
+
+// TransientOwner describes the external entity the lifecycle of a
+// transient Spec is tied to. The owner is recorded in the annotations
+// of the Spec, so stale Specs can be found and removed later even if
+// the driver which wrote them is gone.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TransientOwner {
+    // Claim ties the Spec to the resource claim with the given UID. The
+    // Spec is stale once the claim is no longer live.
+    Claim(String),
+    // Ttl lets the Spec expire after the given duration.
+    Ttl(Duration),
+    // Pid ties the Spec to the process with the given PID. The Spec is
+    // stale once the process has exited. PIDs are only meaningful in the
+    // PID namespace of the sweeping process.
+    Pid(u32),
+}
+
+impl TransientOwner {
+    // annotate records the owner in the given Spec annotations.
+    fn annotate(&self, annotations: &mut BTreeMap<String, String>) -> Result<()> {
+        let (key, value) = match self {
+            TransientOwner::Claim(uid) => {
+                if uid.is_empty() {
+                    return Err(anyhow!("invalid transient Spec owner, empty claim UID"));
+                }
+                (TRANSIENT_CLAIM_ANNOTATION, uid.clone())
+            }
+            TransientOwner::Ttl(ttl) => {
+                let expires = SystemTime::now()
+                    .checked_add(*ttl)
+                    .ok_or_else(|| anyhow!("invalid transient Spec TTL {:?}", ttl))?;
+                let expires = expires
+                    .duration_since(UNIX_EPOCH)
+                    .context("invalid transient Spec expiry")?;
+                (TRANSIENT_EXPIRES_ANNOTATION, expires.as_secs().to_string())
+            }
+            TransientOwner::Pid(pid) => (TRANSIENT_PID_ANNOTATION, pid.to_string()),
+        };
+
+        annotations.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+// is_transient checks if the given Spec annotations record a transient owner.
+pub fn is_transient(annotations: &BTreeMap<String, String>) -> bool {
+    annotations.contains_key(TRANSIENT_CLAIM_ANNOTATION)
+        || annotations.contains_key(TRANSIENT_EXPIRES_ANNOTATION)
+        || annotations.contains_key(TRANSIENT_PID_ANNOTATION)
+}
+
+// is_stale checks if a transient Spec has outlived its owner. Owner
+// annotations which fail to parse never make a Spec stale.
+fn is_stale<F>(annotations: &BTreeMap<String, String>, now: SystemTime, claim_is_live: &F) -> bool
+where
+    F: Fn(&str) -> bool,
+{
+    if let Some(uid) = annotations.get(TRANSIENT_CLAIM_ANNOTATION) {
+        if !claim_is_live(uid) {
+            return true;
+        }
+    }
+
+    if let Some(expires) = annotations
+        .get(TRANSIENT_EXPIRES_ANNOTATION)
+        .and_then(|v| v.parse::<u64>().ok())
+    {
+        if now >= UNIX_EPOCH + Duration::from_secs(expires) {
+            return true;
+        }
+    }
+
+    if let Some(pid) = annotations
+        .get(TRANSIENT_PID_ANNOTATION)
+        .and_then(|v| v.parse::<libc::pid_t>().ok())
+    {
+        if !process_exists(pid) {
+            return true;
+        }
+    }
+
+    false
+}
+
+fn process_exists(pid: libc::pid_t) -> bool {
+    if pid <= 0 {
+        return false;
+    }
+    // Signal 0 only checks for existence; EPERM means it exists but
+    // belongs to somebody else.
+    // SAFETY: kill(2) takes no pointers and signal 0 is never delivered,
+    // so no process, including this one, is affected.
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+impl Cache {
+    // write_transient_spec writes a transient Spec for the given owner.
+    // The name of the Spec file is generated from the vendor and class
+    // of the Spec and transient_id, see generate_transient_spec_name().
+    // The owner is recorded in the Spec annotations. On success the
+    // generated name is returned, which can be passed to remove_spec().
+    pub fn write_transient_spec(
+        &self,
+        raw: &CDISpec,
+        transient_id: &str,
+        owner: &TransientOwner,
+    ) -> Result<String, CdiError> {
+        let name = generate_name_for_transient_spec(raw, transient_id)?;
+
+        let mut raw = raw.clone();
+        owner.annotate(&mut raw.annotations)?;
+        self.write_spec(&raw, &name)?;
+
+        Ok(name)
+    }
+
+    // sweep_transient_specs removes every transient Spec which has outlived
+    // its owner: Specs of claims for which claim_is_live returns false,
+    // expired Specs and Specs of processes which have exited. Only Specs in
+    // dynamic Spec directories are swept, see is_sweepable(). The Cache is
+    // refreshed before and after the sweep. The paths of the removed Spec
+    // files are returned. Removal failures don't stop the sweep; they are
+    // reported together, one CdiError::SpecFile per Spec file, once all
+    // stale Specs have been tried.
+    pub fn sweep_transient_specs<F>(&self, claim_is_live: F) -> Result<Vec<String>, CdiError>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let _ = self.refresh();
+
+        let now = SystemTime::now();
+        let mut stale: Vec<String> = self
+            .snapshot()
+            .specs
+            .values()
+            .flatten()
+            .filter(|spec| self.is_sweepable(&spec.get_path()))
+            .filter(|spec| is_transient(&spec.cdi_spec.annotations))
+            .filter(|spec| is_stale(&spec.cdi_spec.annotations, now, &claim_is_live))
+            .map(|spec| spec.get_path())
+            .collect();
+        stale.sort();
+        stale.dedup();
+
+        let mut removed = Vec::new();
+        let mut failed = Vec::new();
+        for path in stale {
+            match fs::remove_file(&path) {
+                Ok(()) => removed.push(path),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => failed.push(CdiError::spec_file(
+                    Path::new(&path),
+                    anyhow::Error::from(e).context("failed to remove transient Spec"),
+                )),
+            }
+        }
+
+        let _ = self.refresh();
+
+        if !failed.is_empty() {
+            return Err(CdiError::from_errors(failed));
+        }
+
+        Ok(removed)
+    }
+
+    // is_sweepable checks if the Spec file at the given path is in a
+    // dynamic Spec directory, which transient Specs are swept from. These
+    // are the writable Spec directories, except for DEFAULT_STATIC_DIR,
+    // which holds the Specs installed by the admin. A Spec file belongs to
+    // the innermost Spec directory containing it.
+    fn is_sweepable(&self, path: &str) -> bool {
+        self.spec_dirs
+            .iter()
+            .filter(|dir| Path::new(path).starts_with(&dir.path))
+            .max_by_key(|dir| dir.path.len())
+            .is_some_and(|dir| dir.writable && dir.path != DEFAULT_STATIC_DIR)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        spec_dirs::{with_spec_dir, with_spec_dirs, SpecDir},
+        test_utils::{dir_cache, raw_spec},
+    };
+
+    #[test]
+    fn write_transient_spec_records_the_owner() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir_cache(&[dir.path().to_str().unwrap()]);
+
+        let name = cache
+            .write_transient_spec(
+                &raw_spec("vendor.com/gpu", "VENDOR=1"),
+                "claim-1",
+                &TransientOwner::Claim("uid-1".to_string()),
+            )
+            .unwrap();
+        assert_eq!(name, "vendor.com-gpu_claim-1");
+
+        let specs = cache.get_vendor_specs("vendor.com");
+        assert_eq!(specs.len(), 1);
+        assert!(specs[0].get_path().ends_with("vendor.com-gpu_claim-1.yaml"));
+        assert_eq!(
+            specs[0].cdi_spec.annotations[TRANSIENT_CLAIM_ANNOTATION],
+            "uid-1"
+        );
+
+        cache.remove_spec(&name).unwrap();
+        assert!(cache.list_devices().is_empty());
+    }
+
+    #[test]
+    fn write_transient_spec_rejects_empty_claims() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir_cache(&[dir.path().to_str().unwrap()]);
+
+        let err = cache
+            .write_transient_spec(
+                &raw_spec("vendor.com/gpu", "VENDOR=1"),
+                "claim-1",
+                &TransientOwner::Claim(String::new()),
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("empty claim UID"));
+    }
+
+    #[test]
+    fn write_transient_spec_rejects_overflowing_ttls() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir_cache(&[dir.path().to_str().unwrap()]);
+
+        let err = cache
+            .write_transient_spec(
+                &raw_spec("vendor.com/gpu", "VENDOR=1"),
+                "ttl-max",
+                &TransientOwner::Ttl(Duration::MAX),
+            )
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("invalid transient Spec TTL"),
+            "{err}"
+        );
+        assert!(cache.list_devices().is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "miri does not support kill")]
+    fn sweep_removes_only_stale_transient_specs() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir_cache(&[dir.path().to_str().unwrap()]);
+
+        let owners = [
+            ("live-claim", TransientOwner::Claim("live".to_string())),
+            ("dead-claim", TransientOwner::Claim("dead".to_string())),
+            ("expired", TransientOwner::Ttl(Duration::ZERO)),
+            ("valid", TransientOwner::Ttl(Duration::from_secs(3600))),
+            ("live-pid", TransientOwner::Pid(std::process::id())),
+            ("dead-pid", TransientOwner::Pid(i32::MAX as u32)),
+        ];
+        for (id, owner) in &owners {
+            let kind = format!("vendor.com/{}", id);
+            cache
+                .write_transient_spec(&raw_spec(&kind, "VENDOR=1"), id, owner)
+                .unwrap();
+        }
+        cache
+            .write_spec(&raw_spec("vendor.com/static", "VENDOR=1"), "static")
+            .unwrap();
+
+        let removed = cache.sweep_transient_specs(|uid| uid == "live").unwrap();
+
+        let removed: Vec<_> = removed
+            .iter()
+            .map(|p| p.rsplit('/').next().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            removed,
+            vec![
+                "vendor.com-dead-claim_dead-claim.yaml",
+                "vendor.com-dead-pid_dead-pid.yaml",
+                "vendor.com-expired_expired.yaml",
+            ]
+        );
+        assert_eq!(
+            cache.list_devices(),
+            vec![
+                "vendor.com/live-claim=gpu0",
+                "vendor.com/live-pid=gpu0",
+                "vendor.com/static=gpu0",
+                "vendor.com/valid=gpu0",
+            ]
+        );
+
+        // Nothing left to sweep.
+        assert!(cache
+            .sweep_transient_specs(|uid| uid == "live")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn sweep_skips_read_only_spec_dirs() {
+        let vendor = tempfile::tempdir().unwrap();
+        let run = tempfile::tempdir().unwrap();
+        for dir in [&vendor, &run] {
+            dir_cache(&[dir.path().to_str().unwrap()])
+                .write_transient_spec(
+                    &raw_spec("vendor.com/gpu", "VENDOR=1"),
+                    "expired",
+                    &TransientOwner::Ttl(Duration::ZERO),
+                )
+                .unwrap();
+        }
+
+        let mut cache = Cache::default();
+        with_spec_dirs(&[run.path().to_str().unwrap()])(&mut cache);
+        with_spec_dir(SpecDir {
+            writable: false,
+            ..SpecDir::new(vendor.path().to_str().unwrap(), 1)
+        })(&mut cache);
+
+        let removed = cache.sweep_transient_specs(|_| true).unwrap();
+        assert_eq!(
+            removed,
+            vec![run
+                .path()
+                .join("vendor.com-gpu_expired.yaml")
+                .display()
+                .to_string()]
+        );
+        assert!(vendor.path().join("vendor.com-gpu_expired.yaml").exists());
+        assert_eq!(cache.list_devices(), vec!["vendor.com/gpu=gpu0"]);
+    }
+}
@@ -1,177 +1,541 @@
-use anyhow::anyhow;
-use notify::DebouncedEvent;
-use notify::{watcher, RecursiveMode, Watcher};
-use std::collections::HashMap;
-use std::error::Error;
-use std::sync::mpsc::channel;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
-pub struct Watch {
-    watcher: Arc<Mutex<notify::RecommendedWatcher>>,
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use notify::{
+    event::{CreateKind, RemoveKind},
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+
+use crate::{
+    limits::Limits,
+    spec_dirs::{watched_paths, SpecDir},
+    utils::is_cdi_spec,
+};
+
+// DEFAULT_DEBOUNCE is how long the Spec directories need to stay quiet
+// after a change before the Cache is marked for refresh. Writers usually
+// produce a burst of events (create, write, close, rename) per Spec file.
+const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(100);
+
+pub(crate) type DirErrors = HashMap<String, Arc<dyn Error + Send + Sync + 'static>>;
+
+// Listener is called by the monitor thread whenever it marks the Cache
+// stale. It returns false once it is no longer interested.
+pub(crate) type Listener = Box<dyn Fn() -> bool + Send>;
+
+// Watch monitors the Spec directories of a Cache for changes. It does not
+// refresh the Cache itself: the monitor thread only marks the Cache stale
+// and tells its listeners, the next query (or a listener) refreshes it.
+// Directories which are missing when the Watch is set up are retried on
+// every update, so Spec directories created later are picked up too. Like
+// the scan of a Spec dir, the Watch honours its recursion and symlink
+// settings: each directory the scan enters is watched on its own.
+pub(crate) struct Watch {
+    dirs: Option<Vec<SpecDir>>,
+    limits: Limits,
+    watcher: Option<RecommendedWatcher>,
+    // watched are the paths watched below the Spec dirs.
+    watched: HashSet<PathBuf>,
     tracked: Arc<Mutex<HashMap<String, bool>>>,
+    // ancestors are the closest existing parents of missing Spec dirs.
+    // They are watched too, so creating a Spec dir marks the Cache stale.
+    ancestors: HashSet<PathBuf>,
+    stale: Arc<AtomicBool>,
+    // watching is set from setup() until stop().
+    watching: Arc<AtomicBool>,
+    // listeners are kept across setup() and stop().
+    listeners: Arc<Mutex<Vec<Listener>>>,
+    monitor: Option<JoinHandle<()>>,
+    debounce: Duration,
 }
 
-impl Watch {
-    pub fn new() -> Watch {
-        Watch {
-            watcher: Arc::new(Mutex::new(
-                notify::watcher(channel().0, Duration::from_secs(2)).unwrap(),
-            )),
+impl Default for Watch {
+    fn default() -> Self {
+        Self {
+            dirs: None,
+            limits: Limits::default(),
+            watcher: None,
+            watched: HashSet::new(),
             tracked: Arc::new(Mutex::new(HashMap::new())),
+            ancestors: HashSet::new(),
+            stale: Arc::new(AtomicBool::new(false)),
+            watching: Arc::new(AtomicBool::new(false)),
+            listeners: Arc::new(Mutex::new(Vec::new())),
+            monitor: None,
+            debounce: DEFAULT_DEBOUNCE,
         }
     }
+}
 
-    pub fn setup(
-        &mut self,
-        dirs: Vec<String>,
-        dir_errors: &mut HashMap<String, Box<dyn Error + Send + Sync + 'static>>,
-    ) {
-        let mut tracked = HashMap::new();
-        for dir in &dirs {
-            tracked.insert(dir.clone(), false);
+// SharedWatch is the Watch of a Cache. Queries check it without locking:
+// the Watch is only locked when it needs to be set up, stopped or updated,
+// so concurrent queries of an unchanged Cache don't serialize.
+pub(crate) struct SharedWatch {
+    watch: Mutex<Watch>,
+    stale: Arc<AtomicBool>,
+    watching: Arc<AtomicBool>,
+    #[cfg(feature = "async")]
+    listeners: Arc<Mutex<Vec<Listener>>>,
+}
+
+impl Default for SharedWatch {
+    fn default() -> Self {
+        let watch = Watch::default();
+        Self {
+            stale: watch.stale.clone(),
+            watching: watch.watching.clone(),
+            #[cfg(feature = "async")]
+            listeners: watch.listeners.clone(),
+            watch: Mutex::new(watch),
         }
-        self.tracked = Arc::new(Mutex::new(tracked));
+    }
+}
 
-        let (tx, rx) = std::sync::mpsc::channel();
-        match watcher(tx, Duration::from_secs(2)) {
-            Ok(mut watch) => {
-                for dir in dirs.iter() {
-                    if let Err(e) = watch.watch(dir, RecursiveMode::Recursive) {
-                        dir_errors.insert(dir.clone(), Box::new(e));
-                    } else {
-                        self.tracked.lock().unwrap().insert(dir.clone(), true);
-                    }
-                }
-                self.watcher = Arc::new(Mutex::new(watch));
-            }
+impl SharedWatch {
+    // get_mut returns the Watch for changing it.
+    pub(crate) fn get_mut(&mut self) -> &mut Watch {
+        self.watch.get_mut().unwrap()
+    }
+
+    // listen adds a listener which is called whenever the Watch notices a
+    // change. The Watch still has to be set up by check() for that.
+    #[cfg(feature = "async")]
+    pub(crate) fn listen(&self, listener: Listener) {
+        self.listeners.lock().unwrap().push(listener);
+    }
+
+    // check sets up or stops the Watch for the given refresh mode and
+    // returns true if the Cache needs to be refreshed. The dir_errors are
+    // only locked if the Watch needs to change or has seen changes.
+    pub(crate) fn check(
+        &self,
+        auto_refresh: bool,
+        dirs: &[SpecDir],
+        limits: &Limits,
+        dir_errors: &Mutex<DirErrors>,
+    ) -> bool {
+        if auto_refresh == self.watching.load(Ordering::Acquire)
+            && !self.stale.load(Ordering::Acquire)
+        {
+            return false;
+        }
+
+        let mut watch = self.watch.lock().unwrap();
+        if !auto_refresh {
+            watch.stop();
+            return false;
+        }
+
+        let mut dir_errors = dir_errors.lock().unwrap();
+        let mut update = false;
+        if !watch.is_watching(dirs, limits) {
+            watch.setup(dirs, limits, &mut dir_errors);
+            update = true;
+        }
+        update | watch.update(&mut dir_errors)
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl Watch {
+    // set_debounce sets how long the Spec directories need to stay quiet
+    // after a change. It is used by the next setup().
+    pub(crate) fn set_debounce(&mut self, debounce: Duration) {
+        self.debounce = debounce;
+    }
+
+    // is_watching returns true if the Watch is set up for exactly the
+    // given dirs and limits.
+    pub(crate) fn is_watching(&self, dirs: &[SpecDir], limits: &Limits) -> bool {
+        self.dirs.as_deref() == Some(dirs) && self.limits == *limits
+    }
+
+    // setup starts monitoring the given Spec directories, stopping any
+    // previous monitoring first. The limits bound the subdirectories
+    // watched like those scanned. If no watcher can be created, update
+    // keeps reporting the Cache stale so every query rescans the dirs.
+    pub(crate) fn setup(&mut self, dirs: &[SpecDir], limits: &Limits, dir_errors: &mut DirErrors) {
+        self.stop();
+        self.dirs = Some(dirs.to_vec());
+        self.limits = *limits;
+        self.watching.store(true, Ordering::Release);
+
+        let (tx, rx) = channel();
+        let watcher = match notify::recommended_watcher(tx) {
+            Ok(watcher) => watcher,
             Err(e) => {
+                let err = dir_error(e, "failed to create watcher");
                 for dir in dirs {
-                    dir_errors.insert(dir, Box::new(e));
+                    dir_errors.insert(dir.path.clone(), err.clone());
                 }
+                self.stale.store(true, Ordering::Release);
+                return;
+            }
+        };
+
+        {
+            let mut tracked = self.tracked.lock().unwrap();
+            for dir in dirs {
+                tracked.insert(dir.path.clone(), false);
             }
         }
-        self.update(dir_errors, Vec::new());
+
+        let tracked = self.tracked.clone();
+        let stale = self.stale.clone();
+        let listeners = self.listeners.clone();
+        let debounce = self.debounce;
+        self.monitor = Some(thread::spawn(move || {
+            monitor(rx, &tracked, &stale, &listeners, debounce)
+        }));
+        self.watcher = Some(watcher);
+
+        self.update(dir_errors);
     }
 
-    fn start(
-        &self,
-        refresh: impl Fn() -> Result<(), Box<dyn std::error::Error>> + Send + 'static + Clone,
-        dir_errors: &mut HashMap<String, Box<dyn std::error::Error + Send + Sync + 'static>>,
-    ) {
-        let refresh_clone = refresh.clone();
-
-        thread::spawn(move || {
-            // Assuming `watch` is adapted to be callable in this context.
-            // You might need to pass additional parameters or clone other necessary data.
-            self.watch(refresh_clone, dir_errors);
-        });
-    }
-    pub fn stop(&self) {
-        /*
-        let mut watcher = match self.watcher.lock() {
-            Ok(guard) => guard,
-            Err(poisoned) => poisoned.into_inner(),
+    // stop monitoring. Dropping the watcher closes its event channel,
+    // which terminates the monitor thread.
+    pub(crate) fn stop(&mut self) {
+        self.watcher = None;
+        if let Some(monitor) = self.monitor.take() {
+            let _ = monitor.join();
+        }
+        self.tracked.lock().unwrap().clear();
+        self.watched.clear();
+        self.ancestors.clear();
+        self.stale.store(false, Ordering::Release);
+        self.watching.store(false, Ordering::Release);
+        self.dirs = None;
+    }
+
+    // update tries to start watching any Spec directory which is not yet
+    // watched and consumes pending change notifications. It returns true
+    // if the Cache needs to be refreshed. Without a watcher the Cache is
+    // always stale. After changes the Spec dirs are walked again, to watch
+    // new subdirectories and symlinked Spec files and to drop the watches
+    // of those which are gone.
+    pub(crate) fn update(&mut self, dir_errors: &mut DirErrors) -> bool {
+        let mut update = self.stale.swap(false, Ordering::AcqRel);
+
+        let (Some(watcher), Some(dirs)) = (self.watcher.as_mut(), self.dirs.as_ref()) else {
+            self.stale.store(self.dirs.is_some(), Ordering::Release);
+            return self.dirs.is_some();
         };
-         */
-        let mut watcher = self.watcher.lock().unwrap();
+
         let mut tracked = self.tracked.lock().unwrap();
+        if !update && tracked.values().all(|ok| *ok) {
+            return false;
+        }
+
+        let mut watched = HashSet::new();
+        for dir in dirs {
+            let Some(ok) = tracked.get_mut(&dir.path) else {
+                continue;
+            };
+
+            match watcher.watch(Path::new(&dir.path), RecursiveMode::NonRecursive) {
+                Ok(()) => {
+                    if !*ok {
+                        *ok = true;
+                        dir_errors.remove(&dir.path);
+                        update = true;
+                    }
+                    watched.insert(PathBuf::from(&dir.path));
+                    for path in watched_paths(dir, &self.limits) {
+                        // Paths removed meanwhile are noticed by the next
+                        // refresh, which scans the Spec dir anyway.
+                        if watcher.watch(&path, RecursiveMode::NonRecursive).is_ok() {
+                            watched.insert(path);
+                        }
+                    }
+                }
+                Err(e) => {
+                    *ok = false;
+                    dir_errors.insert(
+                        dir.path.clone(),
+                        dir_error(e, "failed to monitor for changes"),
+                    );
+                    // Creating the directory is noticed in its closest
+                    // existing parent, then watching it is retried.
+                    let ancestor = Path::new(&dir.path)
+                        .ancestors()
+                        .skip(1)
+                        .find(|p| p.is_dir());
+                    if let Some(ancestor) = ancestor {
+                        if !self.ancestors.contains(ancestor)
+                            && watcher.watch(ancestor, RecursiveMode::NonRecursive).is_ok()
+                        {
+                            self.ancestors.insert(ancestor.to_path_buf());
+                        }
+                    }
+                }
+            }
+        }
 
-        for (dir, _) in tracked.iter() {
-            if let Err(e) = watcher.unwatch(dir) {
-                println!("Error stopping watcher: {:?}", e);
+        for path in self.watched.difference(&watched) {
+            if !self.ancestors.contains(path) {
+                let _ = watcher.unwatch(path);
             }
         }
-        tracked.clear();
+        self.watched = watched;
+
+        update
     }
+}
 
-    fn watch(
-        &self,
-        refresh: impl Fn() -> Result<(), Box<dyn std::error::Error>> + Send + 'static,
-        dir_errors: &mut HashMap<String, Box<dyn std::error::Error + Send + Sync + 'static>>,
-    ) {
-        let (tx, rx) = channel();
-        let mut watcher = watcher(tx, Duration::from_secs(10)).unwrap();
+// dir_error wraps a watcher error for recording it against a Spec dir.
+fn dir_error<E>(err: E, context: &'static str) -> Arc<dyn Error + Send + Sync + 'static>
+where
+    E: Into<anyhow::Error>,
+{
+    let err: Box<dyn Error + Send + Sync + 'static> = err.into().context(context).into();
+    err.into()
+}
 
-        // Assuming you've already added directories to watch somewhere
-        // for dir in self.tracked.lock().unwrap().keys() {
-        //     watcher.watch(dir, RecursiveMode::Recursive).unwrap();
-        // }
+// monitor receives the watcher events, waits for the Spec directories to
+// settle and then marks the Cache stale and tells the listeners.
+fn monitor(
+    rx: Receiver<notify::Result<Event>>,
+    tracked: &Mutex<HashMap<String, bool>>,
+    stale: &AtomicBool,
+    listeners: &Mutex<Vec<Listener>>,
+    debounce: Duration,
+) {
+    while let Ok(event) = rx.recv() {
+        if !is_relevant(&event, tracked) {
+            continue;
+        }
 
         loop {
-            match rx.recv() {
-                Ok(event) => match event {
-                    DebouncedEvent::Write(path)
-                    | DebouncedEvent::Remove(path)
-                    | DebouncedEvent::Rename(_, path) => {
-                        if path
-                            .extension()
-                            .map_or(true, |ext| ext != "json" && ext != "yaml")
-                        {
-                            continue;
-                        }
+            match rx.recv_timeout(debounce) {
+                Ok(event) => {
+                    is_relevant(&event, tracked);
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
 
-                        let mut tracked = self.tracked.lock().unwrap();
-                        let file_name = path.to_str().unwrap_or_default().to_string();
+        stale.store(true, Ordering::Release);
+        listeners.lock().unwrap().retain(|listener| listener());
+    }
+}
 
-                        if let DebouncedEvent::Remove(_) = event {
-                            if *tracked.get(&file_name).unwrap_or(&false) {
-                                self.update(dir_errors, vec![file_name]);
-                            } else {
-                                self.update(dir_errors, Vec::new());
-                            }
-                        }
-                        refresh().unwrap(); // Handle error as needed
-                    }
-                    _ => continue,
-                },
-                Err(_) => break,
+// is_relevant checks if an event may change the content of the Cache.
+// Plain accesses are ignored, since refreshing the Cache reads the Spec
+// files itself. So are events in the parents of missing Spec dirs, except
+// for creating (a parent of) a Spec dir. A removed Spec directory is marked
+// for re-watching.
+fn is_relevant(event: &notify::Result<Event>, tracked: &Mutex<HashMap<String, bool>>) -> bool {
+    // Errors (for instance an event queue overflow) may hide changes.
+    let Ok(event) = event else {
+        return true;
+    };
+
+    let concerns_spec_dirs = {
+        let tracked = tracked.lock().unwrap();
+        event.paths.iter().any(|path| {
+            tracked.iter().any(|(dir, ok)| {
+                if *ok {
+                    path.starts_with(dir)
+                } else {
+                    Path::new(dir).starts_with(path)
+                }
+            })
+        })
+    };
+    if !concerns_spec_dirs {
+        return false;
+    }
+
+    match event.kind {
+        EventKind::Create(CreateKind::Folder) | EventKind::Remove(RemoveKind::Folder) => {}
+        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) => {
+            if !event.paths.iter().any(|path| is_cdi_spec(path)) {
+                return false;
             }
         }
+        _ => return false,
     }
 
-    pub fn update(
-        &self,
-        dir_errors: &mut HashMap<String, Box<dyn std::error::Error + Send + Sync + 'static>>,
-        removed: Vec<String>,
-    ) -> bool {
-        let mut update = false;
-        let mut watcher = self.watcher.lock().unwrap();
-        let mut tracked = self.tracked.lock().unwrap();
-
-        // Check and add directories that are not yet being watched.
-        for (dir, &ok) in tracked.iter() {
-            if ok {
-                continue;
+    if let EventKind::Remove(_) = event.kind {
+        let mut tracked = tracked.lock().unwrap();
+        for path in &event.paths {
+            if let Some(ok) = path.to_str().and_then(|dir| tracked.get_mut(dir)) {
+                *ok = false;
             }
+        }
+    }
 
-            match watcher.watch(dir, RecursiveMode::Recursive) {
-                Ok(_) => {
-                    tracked.insert(dir.clone(), true);
-                    dir_errors.remove(dir);
-                    update = true;
-                }
-                Err(e) => {
-                    tracked.insert(dir.clone(), false);
-                    let error = anyhow!("failed to monitor for changes: {}", e);
-                    let error_ref: &(dyn std::error::Error + Send + Sync + 'static) =
-                        error.as_ref();
-                    let boxed_error = Box::new(error_ref);
-                    dir_errors.insert(dir.clone(), boxed_error);
-                }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, time::Instant};
+
+    fn wait_for_update(watch: &mut Watch, dir_errors: &mut DirErrors) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if watch.update(dir_errors) {
+                return true;
             }
+            thread::sleep(Duration::from_millis(20));
         }
+        false
+    }
 
-        // Mark removed directories as not tracked and update errors.
-        for dir in removed.iter() {
-            tracked.insert(dir.clone(), false);
-            let error = anyhow!("directory removed".to_string());
-            let error_ref: &(dyn std::error::Error + Send + Sync + 'static) = error.as_ref();
-            let boxed_error = Box::new(error_ref);
-            dir_errors.insert(dir.clone(), boxed_error);
-            update = true;
+    #[test]
+    #[cfg_attr(miri, ignore = "miri does not support inotify")]
+    fn only_spec_file_changes_mark_the_cache_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let dirs = vec![SpecDir::new(dir.path().to_str().unwrap(), 0)];
+        let mut dir_errors = DirErrors::new();
+        let mut watch = Watch::default();
+
+        watch.setup(&dirs, &Limits::default(), &mut dir_errors);
+        assert!(watch.is_watching(&dirs, &Limits::default()));
+        assert!(dir_errors.is_empty());
+        // The initial update found the directory and started watching it.
+        assert!(!watch.update(&mut dir_errors));
+
+        fs::write(dir.path().join("README.txt"), "not a spec").unwrap();
+        thread::sleep(DEFAULT_DEBOUNCE * 3);
+        assert!(!watch.update(&mut dir_errors));
+
+        fs::write(dir.path().join("vendor.yaml"), "kind: vendor.com/device").unwrap();
+        assert!(wait_for_update(&mut watch, &mut dir_errors));
+        assert!(!watch.update(&mut dir_errors));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "miri does not support inotify")]
+    fn spec_dirs_created_later_are_picked_up() {
+        let parent = tempfile::tempdir().unwrap();
+        let dir = parent.path().join("cdi");
+        let dirs = vec![SpecDir::new(dir.to_str().unwrap(), 0)];
+        let mut dir_errors = DirErrors::new();
+        let mut watch = Watch::default();
+
+        watch.setup(&dirs, &Limits::default(), &mut dir_errors);
+        assert!(dir_errors[&dirs[0].path]
+            .to_string()
+            .contains("failed to monitor for changes"));
+        assert!(!watch.update(&mut dir_errors));
+
+        fs::create_dir(&dir).unwrap();
+        assert!(watch.update(&mut dir_errors));
+        assert!(dir_errors.is_empty());
+
+        fs::write(dir.join("vendor.json"), "{}").unwrap();
+        assert!(wait_for_update(&mut watch, &mut dir_errors));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "miri does not support inotify")]
+    fn watched_paths_follow_the_spec_dir_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+        let spec_dir = dir.path().join("cdi");
+        fs::create_dir_all(spec_dir.join("sub")).unwrap();
+        fs::write(target.path().join("vendor.yaml"), "kind: vendor.com/device").unwrap();
+        std::os::unix::fs::symlink(
+            target.path().join("vendor.yaml"),
+            spec_dir.join("linked.yaml"),
+        )
+        .unwrap();
+
+        let flat = SpecDir {
+            recursive: false,
+            follow_symlinks: false,
+            ..SpecDir::new(spec_dir.to_str().unwrap(), 0)
+        };
+        let mut dir_errors = DirErrors::new();
+        let mut watch = Watch::default();
+        watch.setup(&[flat], &Limits::default(), &mut dir_errors);
+        assert!(dir_errors.is_empty());
+        assert!(!watch.update(&mut dir_errors));
+
+        // Neither subdirectories nor symlink targets are scanned.
+        fs::write(spec_dir.join("sub/vendor.yaml"), "kind: vendor.com/device").unwrap();
+        fs::write(target.path().join("vendor.yaml"), "kind: other.com/device").unwrap();
+        thread::sleep(DEFAULT_DEBOUNCE * 3);
+        assert!(!watch.update(&mut dir_errors));
+
+        let dirs = vec![SpecDir::new(spec_dir.to_str().unwrap(), 0)];
+        watch.setup(&dirs, &Limits::default(), &mut dir_errors);
+        assert!(!watch.update(&mut dir_errors));
+
+        fs::write(target.path().join("vendor.yaml"), "kind: vendor.com/device").unwrap();
+        assert!(wait_for_update(&mut watch, &mut dir_errors));
+
+        // Subdirectories created later are watched too.
+        fs::create_dir(spec_dir.join("sub/nested")).unwrap();
+        assert!(wait_for_update(&mut watch, &mut dir_errors));
+        fs::write(spec_dir.join("sub/nested/vendor.json"), "{}").unwrap();
+        assert!(wait_for_update(&mut watch, &mut dir_errors));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "miri does not support inotify")]
+    fn checking_an_unchanged_watch_does_not_lock_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let dirs = vec![SpecDir::new(dir.path().to_str().unwrap(), 0)];
+        let shared = Arc::new(SharedWatch::default());
+        let dir_errors = Arc::new(Mutex::new(DirErrors::new()));
+
+        assert!(shared.check(true, &dirs, &Limits::default(), &dir_errors));
+        assert!(!shared.check(true, &dirs, &Limits::default(), &dir_errors));
+
+        // With the Watch and the dir errors locked, checks still return.
+        let (tx, rx) = channel();
+        {
+            let _watch = shared.watch.lock().unwrap();
+            let _dir_errors = dir_errors.lock().unwrap();
+            let (shared, dirs, dir_errors) = (shared.clone(), dirs.clone(), dir_errors.clone());
+            thread::spawn(move || {
+                let update = shared.check(true, &dirs, &Limits::default(), &dir_errors);
+                tx.send(update).unwrap();
+            });
+            assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(false));
         }
 
-        update
+        fs::write(dir.path().join("vendor.yaml"), "kind: vendor.com/device").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !shared.check(true, &dirs, &Limits::default(), &dir_errors) {
+            assert!(Instant::now() < deadline, "change not noticed");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "miri does not support inotify")]
+    fn stop_terminates_the_monitor() {
+        let dir = tempfile::tempdir().unwrap();
+        let dirs = vec![SpecDir::new(dir.path().to_str().unwrap(), 0)];
+        let mut watch = Watch::default();
+
+        watch.setup(&dirs, &Limits::default(), &mut DirErrors::new());
+        assert!(watch.monitor.is_some());
+
+        watch.stop();
+        assert!(watch.monitor.is_none());
+        assert!(!watch.is_watching(&dirs, &Limits::default()));
+        assert!(!watch.update(&mut DirErrors::new()));
     }
 }
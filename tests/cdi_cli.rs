@@ -1,7 +1,7 @@
 // Spawns the cdi binary; miri cannot emulate process creation.
 #![cfg(not(miri))]
 
-use std::{fs, process::Command};
+use std::{fs, path::Path, process::Command};
 
 fn cdi_bin() -> &'static str {
     env!("CARGO_BIN_EXE_cdi")
@@ -29,6 +29,65 @@ fn cdi_cli_lists_devices() {
     );
 }
 
+// cdi_with_spec_dir returns a cdi command using only the given Spec dir
+// and the empty configuration file config, whatever the configuration of
+// the host.
+fn cdi_with_spec_dir(dir: &Path, config: &Path) -> Command {
+    let mut cmd = Command::new(cdi_bin());
+    cmd.env("CDI_CONFIG_FILE", config)
+        .env("CDI_SPEC_DIRS", dir)
+        .env("CDI_AUTO_REFRESH", "false");
+    cmd
+}
+
+#[test]
+fn cdi_cli_lists_classes() {
+    let dir = tempfile::tempdir().unwrap();
+    // An empty configuration file keeps the defaults.
+    let config = tempfile::NamedTempFile::new().unwrap();
+
+    let output = cdi_with_spec_dir(dir.path(), config.path())
+        .arg("classes")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "No CDI device classes found.\n"
+    );
+
+    for (file, kind) in [
+        ("vendor-gpu.json", "vendor.com/gpu"),
+        ("vendor-nic.json", "vendor.com/nic"),
+        ("other-gpu.json", "other.com/gpu"),
+    ] {
+        fs::write(
+            dir.path().join(file),
+            format!(
+                r#"{{"cdiVersion": "0.6.0", "kind": "{kind}",
+  "devices": [{{"name": "dev0", "containerEdits": {{"env": ["A=1"]}}}}]}}"#
+            ),
+        )
+        .unwrap();
+    }
+
+    let output = cdi_with_spec_dir(dir.path(), config.path())
+        .arg("classes")
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "CDI device classes found:\n\
+         \x20 0. gpu (2 vendors: other.com, vendor.com)\n\
+         \x20 1. nic (1 vendors: vendor.com)\n"
+    );
+}
+
 #[test]
 fn cdi_cli_lists_devices_verbose_json() {
     let output = Command::new(cdi_bin())
@@ -56,16 +115,21 @@ fn cdi_cli_inject_rejects_missing_oci_spec() {
 #[test]
 fn cdi_cli_inject_updates_an_oci_spec() {
     let dir = tempfile::tempdir().unwrap();
-    let spec = dir.path().join("oci.yaml");
+    let config = tempfile::NamedTempFile::new().unwrap();
+    fs::write(
+        dir.path().join("vendor.json"),
+        r#"{"cdiVersion": "0.6.0", "kind": "vendor.example/gpu",
+  "devices": [{"name": "gpu0", "containerEdits": {"env": ["GPU=0"]}}]}"#,
+    )
+    .unwrap();
+    let spec = tempfile::NamedTempFile::new().unwrap();
     fs::write(&spec, "ociVersion: \"1.0.2\"\n").unwrap();
 
-    // Unknown device patterns are filtered out (not an error): the spec is
-    // echoed back updated-but-unchanged.
-    let output = Command::new(cdi_bin())
+    let output = cdi_with_spec_dir(dir.path(), config.path())
         .args([
             "inject",
-            spec.to_str().unwrap(),
-            "vendor.example/none=missing",
+            spec.path().to_str().unwrap(),
+            "vendor.example/gpu=gpu0",
         ])
         .output()
         .unwrap();
@@ -76,4 +140,54 @@ fn cdi_cli_inject_updates_an_oci_spec() {
     );
     let stdout = String::from_utf8_lossy(&output.stdout);
     assert!(stdout.contains("Updated OCI Spec:"), "{stdout}");
+    assert!(stdout.contains("GPU=0"), "{stdout}");
+
+    // Unknown device names fail like glob patterns matching no device.
+    let output = cdi_with_spec_dir(dir.path(), config.path())
+        .args([
+            "inject",
+            spec.path().to_str().unwrap(),
+            "vendor.example/gpu=gpu0",
+            "vendor.example/none=missing",
+            "vendor.example/none=*",
+        ])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(
+            "unresolvable CDI devices vendor.example/none=missing, vendor.example/none=*"
+        ),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn cdi_cli_inject_fails_on_missing_host_device_nodes() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = tempfile::NamedTempFile::new().unwrap();
+    fs::write(
+        dir.path().join("vendor.json"),
+        r#"{"cdiVersion": "0.6.0", "kind": "vendor.example/gpu",
+  "devices": [{"name": "gpu0",
+    "containerEdits": {"deviceNodes": [{"path": "/nonexistent/device/node"}]}}]}"#,
+    )
+    .unwrap();
+    let spec = tempfile::NamedTempFile::new().unwrap();
+    fs::write(&spec, "ociVersion: \"1.0.2\"\n").unwrap();
+
+    let output = cdi_with_spec_dir(dir.path(), config.path())
+        .args([
+            "inject",
+            spec.path().to_str().unwrap(),
+            "vendor.example/gpu=gpu0",
+        ])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!stdout.contains("Updated OCI Spec:"), "{stdout}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("/nonexistent/device/node"), "{stderr}");
 }